@@ -13,7 +13,7 @@ struct Foo {
 
 #[cfg(test)]
 mod tests {
-    use swanky_persist::{Cache, Cacheable, Persist, Persistable};
+    use swanky_persist::{Cache, CacheFormat, Cacheable, Persist, Persistable};
 
     #[test]
     fn test_defaults() {
@@ -35,6 +35,9 @@ mod tests {
 
         assert_eq!(Foo::cache_path(), "foo-path");
         assert_eq!(Foo::cache_expiry(), 3600);
+        assert_eq!(Foo::cache_miss_expiry(), None);
+        assert_eq!(Foo::cache_version(), None);
+        assert_eq!(Foo::cache_format(), CacheFormat::Json);
         assert_eq!(foo.cache_id(), "my_id");
     }
 
@@ -80,4 +83,18 @@ mod tests {
         assert_eq!(BAR_CACHE_EXPIRY, 360);
         assert_eq!(Bar::cache_expiry(), 360);
     }
+
+    #[test]
+    fn test_cache_options() {
+        #[derive(Cache)]
+        #[cache(miss_expiry = 30, version = 2, format = "cbor")]
+        struct Bar {
+            #[cache(id)]
+            id: String,
+        }
+        assert_eq!(Bar::cache_expiry(), 3600);
+        assert_eq!(Bar::cache_miss_expiry(), Some(30));
+        assert_eq!(Bar::cache_version(), Some(2));
+        assert_eq!(Bar::cache_format(), CacheFormat::Cbor);
+    }
 }
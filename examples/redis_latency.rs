@@ -0,0 +1,132 @@
+//! Compare cache latency under concurrent load when opening a Redis connection per call, as
+//! `Cache` used to, against the shared `ConnectionManager` it uses now.
+//!
+//! Needs a running Redis.  `SWANKY_CACHE_URI` defaults to `redis://127.0.0.1`.
+//!
+//! ```bash
+//! cargo run --release --example redis_latency -- [tasks] [operations per task]
+//! ```
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use redis::AsyncCommands;
+use swanky_persist::{Cache, CacheStore, Cacheable, DaoResult, DataServicesConfig};
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct Demo {
+    id: String,
+    payload: String,
+}
+
+impl Cacheable for Demo {
+    fn cache_path() -> &'static str {
+        "redis_latency"
+    }
+    fn cache_id(&self) -> String {
+        self.id.clone()
+    }
+    fn cache_expiry() -> usize {
+        60
+    }
+}
+
+fn demo(task: usize, op: usize) -> Demo {
+    Demo {
+        id: format!("{}-{}", task, op),
+        payload: "x".repeat(256),
+    }
+}
+
+/// Run `tasks` concurrent tasks of `ops` put + fetch pairs each, timing every pair.
+async fn run<F, Fut>(tasks: usize, ops: usize, op: F) -> (Duration, Vec<Duration>)
+where
+    F: Fn(Demo) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = DaoResult<()>> + Send,
+{
+    let started = Instant::now();
+    let handles = (0..tasks)
+        .map(|task| {
+            let op = op.clone();
+            tokio::spawn(async move {
+                let mut latencies = Vec::with_capacity(ops);
+                for i in 0..ops {
+                    let begun = Instant::now();
+                    op(demo(task, i)).await.expect("cache operation failed");
+                    latencies.push(begun.elapsed());
+                }
+                latencies
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut latencies = Vec::with_capacity(tasks * ops);
+    for handle in handles {
+        latencies.extend(handle.await.expect("task panicked"));
+    }
+    latencies.sort();
+    (started.elapsed(), latencies)
+}
+
+fn report(name: &str, (elapsed, latencies): (Duration, Vec<Duration>)) {
+    let percentile = |p: usize| latencies[(latencies.len() * p / 100).min(latencies.len() - 1)];
+    println!(
+        "{:<24} {:>8.0} ops/s   p50 {:>8.2?}   p99 {:>8.2?}   max {:>8.2?}",
+        name,
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentile(50),
+        percentile(99),
+        latencies[latencies.len() - 1],
+    );
+}
+
+#[tokio::main]
+async fn main() -> DaoResult<()> {
+    let mut args = std::env::args().skip(1);
+    let tasks = args.next().and_then(|a| a.parse().ok()).unwrap_or(64);
+    let ops = args.next().and_then(|a| a.parse().ok()).unwrap_or(200);
+
+    let config = Arc::new(DataServicesConfig {
+        db_database: String::new(),
+        db_app_name: String::new(),
+        db_uri: String::new(),
+        cache_uri: std::env::var("SWANKY_CACHE_URI")
+            .unwrap_or_else(|_| "redis://127.0.0.1".to_string()),
+        strict_deletes: false,
+        cache_load_lock: None,
+        cache_self_heal: false,
+    });
+    let cache = Cache::new(config).await?;
+    println!("{} tasks x {} put + fetch pairs", tasks, ops);
+
+    let client = cache.client.clone();
+    let per_call = run(tasks, ops, move |value: Demo| {
+        let client = client.clone();
+        async move {
+            let key = format!("{}:{}", Demo::cache_path(), value.cache_id());
+            let data = serde_json::to_vec(&value).expect("serializable");
+            let mut con = client.get_async_connection().await?;
+            con.set_ex::<_, _, ()>(&key, data, Demo::cache_expiry())
+                .await?;
+            let mut con = client.get_async_connection().await?;
+            con.get::<_, Vec<u8>>(&key).await?;
+            Ok(())
+        }
+    })
+    .await;
+    report("connection per call", per_call);
+
+    let shared = run(tasks, ops, move |value: Demo| {
+        let cache = cache.clone();
+        async move {
+            cache.put(&value).await?;
+            cache.fetch::<Demo>(&value.cache_id()).await?;
+            Ok(())
+        }
+    })
+    .await;
+    report("connection manager", shared);
+    Ok(())
+}
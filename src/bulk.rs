@@ -0,0 +1,42 @@
+/// Outcome reporting for bulk operations such as
+/// [PersistStore::add_many](crate::PersistStore::add_many).
+///
+/// A bulk operation carries on past items that fail, so it only returns an error itself when the
+/// whole batch could not be attempted.  Everything else is reported per item in a [BulkReport].
+use crate::DaoError;
+
+#[derive(Debug, Default)]
+pub struct BulkReport {
+    /// One entry per item, in the order given: the item's id and any error it hit
+    pub results: Vec<(String, Result<(), DaoError>)>,
+}
+
+impl BulkReport {
+    /// Ids of the items that succeeded.
+    pub fn succeeded(&self) -> impl Iterator<Item = &str> {
+        self.results
+            .iter()
+            .filter(|(_, result)| result.is_ok())
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// Ids of the items that failed, with their errors.
+    pub fn failed(&self) -> impl Iterator<Item = (&str, &DaoError)> {
+        self.results
+            .iter()
+            .filter_map(|(id, result)| result.as_ref().err().map(|err| (id.as_str(), err)))
+    }
+
+    /// Whether every item succeeded.
+    pub fn is_complete(&self) -> bool {
+        self.results.iter().all(|(_, result)| result.is_ok())
+    }
+}
+
+impl FromIterator<(String, Result<(), DaoError>)> for BulkReport {
+    fn from_iter<I: IntoIterator<Item = (String, Result<(), DaoError>)>>(iter: I) -> Self {
+        Self {
+            results: iter.into_iter().collect(),
+        }
+    }
+}
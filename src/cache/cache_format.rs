@@ -0,0 +1,150 @@
+/// Encoding of cached values in their [Cacheable::cache_format].
+///
+/// JSON is stored as is, so entries written before formats were selectable still decode.  Every
+/// other format is prefixed with a one byte tag that can't start a JSON document, so a reader
+/// decodes whatever format an entry was written in, whatever the type currently asks for.  That
+/// lets a type move to a new format while entries in the old one are still cached.
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{BoxedError, CacheFormat, Cacheable, DaoError, DaoResult};
+
+const MESSAGE_PACK_TAG: u8 = 1;
+const CBOR_TAG: u8 = 2;
+const BINCODE_TAG: u8 = 3;
+
+/// Encode a value for the cache, in its type's [Cacheable::cache_format].
+pub(crate) fn encode<T: Cacheable + Serialize>(value: &T, cache_key: &str) -> DaoResult<Vec<u8>> {
+    write(T::cache_format(), value).map_err(|e| DaoError::serialization::<T>(cache_key, e))
+}
+
+/// Decode a cached value, in whichever format it was written.
+pub(crate) fn decode<T: DeserializeOwned>(data: &[u8], cache_key: &str) -> DaoResult<T> {
+    read(data).map_err(|e| DaoError::deserialization::<T>(cache_key, e))
+}
+
+fn write<T: Serialize>(format: CacheFormat, value: &T) -> Result<Vec<u8>, BoxedError> {
+    Ok(match format {
+        CacheFormat::Json => serde_json::to_vec(value)?,
+        #[cfg(feature = "msgpack")]
+        CacheFormat::MessagePack => {
+            let mut data = vec![MESSAGE_PACK_TAG];
+            rmp_serde::encode::write_named(&mut data, value)?;
+            data
+        }
+        #[cfg(feature = "cbor")]
+        CacheFormat::Cbor => {
+            let mut data = vec![CBOR_TAG];
+            ciborium::into_writer(value, &mut data)?;
+            data
+        }
+        #[cfg(feature = "bincode")]
+        CacheFormat::Bincode => {
+            let mut data = vec![BINCODE_TAG];
+            bincode::serialize_into(&mut data, value)?;
+            data
+        }
+        format => return Err(format!("the {:?} cache format is not supported", format).into()),
+    })
+}
+
+/// Entries may have been written by a build with more formats enabled than this one.
+fn read<T: DeserializeOwned>(data: &[u8]) -> Result<T, BoxedError> {
+    Ok(match data.split_first() {
+        #[cfg(feature = "msgpack")]
+        Some((&MESSAGE_PACK_TAG, body)) => rmp_serde::from_slice(body)?,
+        #[cfg(not(feature = "msgpack"))]
+        Some((&MESSAGE_PACK_TAG, _)) => return Err(not_enabled("msgpack")),
+        #[cfg(feature = "cbor")]
+        Some((&CBOR_TAG, body)) => ciborium::from_reader(body)?,
+        #[cfg(not(feature = "cbor"))]
+        Some((&CBOR_TAG, _)) => return Err(not_enabled("cbor")),
+        #[cfg(feature = "bincode")]
+        Some((&BINCODE_TAG, body)) => bincode::deserialize(body)?,
+        #[cfg(not(feature = "bincode"))]
+        Some((&BINCODE_TAG, _)) => return Err(not_enabled("bincode")),
+        _ => serde_json::from_slice(data)?,
+    })
+}
+
+#[cfg(not(all(feature = "msgpack", feature = "cbor", feature = "bincode")))]
+fn not_enabled(feature: &str) -> BoxedError {
+    format!("the {} cache format is not enabled", feature).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    struct Demo {
+        id: String,
+        count: u32,
+        tags: Vec<String>,
+    }
+
+    impl Cacheable for Demo {
+        fn cache_path() -> &'static str {
+            "demo"
+        }
+        fn cache_id(&self) -> String {
+            self.id.clone()
+        }
+        fn cache_expiry() -> usize {
+            3600
+        }
+    }
+
+    fn demo() -> Demo {
+        Demo {
+            id: "a".into(),
+            count: 3,
+            tags: vec!["x".into(), "y".into()],
+        }
+    }
+
+    /// Every format this build can write.
+    fn formats() -> Vec<CacheFormat> {
+        vec![
+            CacheFormat::Json,
+            #[cfg(feature = "msgpack")]
+            CacheFormat::MessagePack,
+            #[cfg(feature = "cbor")]
+            CacheFormat::Cbor,
+            #[cfg(feature = "bincode")]
+            CacheFormat::Bincode,
+        ]
+    }
+
+    #[test]
+    fn test_mixed_formats() {
+        for format in formats() {
+            let data = write(format, &demo()).unwrap();
+            assert_eq!(
+                decode::<Demo>(&data, "demo:a").unwrap(),
+                demo(),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn test_untagged_json() {
+        let data = serde_json::to_vec(&demo()).unwrap();
+        assert_eq!(encode(&demo(), "demo:a").unwrap(), data);
+        assert_eq!(decode::<Demo>(&data, "demo:a").unwrap(), demo());
+    }
+
+    #[cfg(not(feature = "cbor"))]
+    #[test]
+    fn test_disabled_format() {
+        assert!(matches!(
+            read::<Demo>(&[CBOR_TAG, 0]),
+            Err(e) if e.to_string().contains("cbor")
+        ));
+        assert!(matches!(
+            decode::<Demo>(&[CBOR_TAG, 0], "demo:a"),
+            Err(DaoError::Deserialization { location, .. }) if location == "demo:a"
+        ));
+    }
+}
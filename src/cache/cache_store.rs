@@ -0,0 +1,99 @@
+/// Backend neutral cache contract.
+/// [DataServices](crate::DataServices) only talks to the cache through this trait, so any
+/// cache that implements it can be swapped in for [Cache](crate::Cache).
+///
+/// Entries are keyed by `{Cacheable::cache_path()}:{Cacheable::cache_id()}`, or by
+/// `{Cacheable::cache_path()}:v{Cacheable::cache_version()}:{Cacheable::cache_id()}` for
+/// versioned types.
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Cacheable, DaoResult};
+
+/// The key a [Cacheable] object with the given id is stored under.
+pub(crate) fn cache_key<T: Cacheable>(id: &str) -> String {
+    match T::cache_version() {
+        Some(version) => format!("{}:v{}:{}", T::cache_path(), version, id),
+        None => format!("{}:{}", T::cache_path(), id),
+    }
+}
+
+/// The key recording that a [Cacheable] type has no object with the given id.
+pub(crate) fn missing_key<T: Cacheable>(id: &str) -> String {
+    format!("swanky_persist:missing:{}", cache_key::<T>(id))
+}
+
+#[async_trait]
+pub trait CacheStore: Clone + Send + Sync {
+    /// Cache the value for [Cacheable::cache_expiry] seconds.
+    async fn put<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Cacheable + Serialize + Send + Sync;
+
+    /// Fetch a cached value by its [Cacheable::cache_id].
+    async fn fetch<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + Cacheable + DeserializeOwned + Unpin + Send + Sync;
+
+    /// Remove a cached value by its [Cacheable::cache_id].  Returns whether anything was cached.
+    async fn delete<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Cacheable;
+
+    /// Cache several values.  Caches that can batch writes override this to make a single round
+    /// trip.
+    async fn put_many<T>(&self, values: &[T]) -> DaoResult<()>
+    where
+        T: Cacheable + Serialize + Send + Sync,
+    {
+        for value in values {
+            self.put(value).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove several cached values by their [Cacheable::cache_id]s.
+    async fn delete_many<T>(&self, ids: &[&str]) -> DaoResult<()>
+    where
+        T: Cacheable,
+    {
+        for id in ids {
+            self.delete::<T>(id).await?;
+        }
+        Ok(())
+    }
+
+    /// Remember that there is no object with this id, for [Cacheable::cache_miss_expiry]
+    /// seconds.  Does nothing for types that don't cache misses.
+    async fn put_missing<T>(&self, id: &str) -> DaoResult<()>
+    where
+        T: Cacheable;
+
+    /// Whether [CacheStore::put_missing] recorded that there is no object with this id.
+    async fn is_missing<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Cacheable;
+
+    /// Forget that there was no object with this id, once one has been added.
+    async fn delete_missing<T>(&self, id: &str) -> DaoResult<()>
+    where
+        T: Cacheable;
+
+    /// Try to take the lock named `key` for at most `ttl`, for coordinating with other processes
+    /// sharing the cache.  Returns the token to [unlock](CacheStore::unlock) it with, or `None`
+    /// if someone else holds it.
+    ///
+    /// A cache only visible to this process has no one else to coordinate with, so by default
+    /// the lock is always granted.
+    async fn try_lock(&self, _key: &str, _ttl: Duration) -> DaoResult<Option<String>> {
+        Ok(Some(String::new()))
+    }
+
+    /// Release a lock taken with [CacheStore::try_lock].  A lock that has since expired and been
+    /// taken by someone else is left alone.
+    async fn unlock(&self, _key: &str, _token: &str) -> DaoResult<()> {
+        Ok(())
+    }
+}
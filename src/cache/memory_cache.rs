@@ -0,0 +1,355 @@
+/// In process cache implementation.
+/// Entries are stored under the same `cache_path:cache_id` keys as [Cache](crate::Cache), expire
+/// after [Cacheable::cache_expiry] seconds, and the least recently used entry is evicted once the
+/// cache is full.
+use async_trait::async_trait;
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    cache::{
+        cache_format::{decode, encode},
+        cache_key, missing_key,
+    },
+    CacheStore, Cacheable, DaoError, DaoResult,
+};
+
+/// Number of entries held by [MemoryCache::default].
+pub const MEMORY_CACHE_DEFAULT_CAPACITY: usize = 10_000;
+
+#[derive(Debug)]
+struct Entry {
+    data: Vec<u8>,
+    expires_at: Instant,
+}
+
+/// Clones share the same underlying entries.
+#[derive(Clone, Debug)]
+pub struct MemoryCache {
+    entries: Arc<Mutex<LruCache<String, Entry>>>,
+    /// Upper bound on [Cacheable::cache_expiry], in seconds.
+    max_expiry: Option<usize>,
+}
+
+impl Default for MemoryCache {
+    fn default() -> Self {
+        Self::new(MEMORY_CACHE_DEFAULT_CAPACITY)
+    }
+}
+
+impl MemoryCache {
+    /// Create a cache holding at most `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Arc::new(Mutex::new(LruCache::new(capacity))),
+            max_expiry: None,
+        }
+    }
+
+    /// Create a cache holding at most `capacity` entries, none of which live longer than
+    /// `max_expiry` seconds regardless of [Cacheable::cache_expiry].
+    pub fn with_max_expiry(capacity: usize, max_expiry: usize) -> Self {
+        Self {
+            max_expiry: Some(max_expiry),
+            ..Self::new(capacity)
+        }
+    }
+
+    /// Number of entries currently held, including any that have expired but not yet been evicted.
+    pub fn len(&self) -> usize {
+        self.lock().map(|entries| entries.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Store already encoded bytes under a cache key.
+    pub(crate) fn put_raw(&self, cache_key: String, data: Vec<u8>, expiry: usize) -> DaoResult<()> {
+        let expiry = match self.max_expiry {
+            Some(max_expiry) => expiry.min(max_expiry),
+            None => expiry,
+        };
+        let entry = Entry {
+            data,
+            expires_at: Instant::now() + Duration::from_secs(expiry as u64),
+        };
+        self.lock()?.put(cache_key, entry);
+        Ok(())
+    }
+
+    /// Drop a cache key, whatever type is stored under it.  Returns whether it was cached.
+    pub(crate) fn invalidate(&self, cache_key: &str) -> DaoResult<bool> {
+        Ok(self.lock()?.pop(cache_key).is_some())
+    }
+
+    /// Whether an unexpired entry is stored under a cache key.  Expired entries are dropped.
+    fn contains(&self, cache_key: &str) -> DaoResult<bool> {
+        let mut entries = self.lock()?;
+        match entries.get(cache_key) {
+            Some(entry) if entry.expires_at > Instant::now() => Ok(true),
+            Some(_) => {
+                entries.pop(cache_key);
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn lock(&self) -> DaoResult<MutexGuard<'_, LruCache<String, Entry>>> {
+        self.entries
+            .lock()
+            .map_err(|_| DaoError::ServiceError("MemoryCache: lock poisoned".to_string()))
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryCache {
+    async fn put<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Cacheable + Serialize + Send + Sync,
+    {
+        let cache_key = cache_key::<T>(&value.cache_id());
+        let data = encode(value, &cache_key)?;
+        self.put_raw(cache_key.clone(), data, T::cache_expiry())?;
+        log::trace!("Cached: {}", &cache_key);
+        Ok(())
+    }
+
+    async fn fetch<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + Cacheable + DeserializeOwned + Unpin + Send + Sync,
+    {
+        let cache_key = cache_key::<T>(id);
+        let mut entries = self.lock()?;
+        match entries.get(&cache_key) {
+            Some(entry) if entry.expires_at > Instant::now() => {
+                let result = decode::<T>(&entry.data, &cache_key)?;
+                log::trace!("Fetched from cache: {}", &cache_key);
+                Ok(Some(result))
+            }
+            Some(_) => {
+                entries.pop(&cache_key);
+                log::trace!("Item expired in cache: {}", &cache_key);
+                Ok(None)
+            }
+            None => {
+                log::trace!("Item not in cache: {}", &cache_key);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn delete<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Cacheable,
+    {
+        let cache_key = cache_key::<T>(id);
+        let deleted = self.invalidate(&cache_key)?;
+        log::trace!("Deleted from cache: {}", &cache_key);
+        Ok(deleted)
+    }
+
+    async fn put_missing<T>(&self, id: &str) -> DaoResult<()>
+    where
+        T: Cacheable,
+    {
+        if let Some(expiry) = T::cache_miss_expiry() {
+            self.put_raw(missing_key::<T>(id), Vec::new(), expiry)?;
+            log::trace!("Cached miss: {}", cache_key::<T>(id));
+        }
+        Ok(())
+    }
+
+    async fn is_missing<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Cacheable,
+    {
+        match T::cache_miss_expiry() {
+            Some(_) => self.contains(&missing_key::<T>(id)),
+            None => Ok(false),
+        }
+    }
+
+    async fn delete_missing<T>(&self, id: &str) -> DaoResult<()>
+    where
+        T: Cacheable,
+    {
+        if T::cache_miss_expiry().is_some() {
+            self.invalidate(&missing_key::<T>(id))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    struct Demo {
+        id: String,
+    }
+
+    impl Cacheable for Demo {
+        fn cache_path() -> &'static str {
+            "demo"
+        }
+        fn cache_id(&self) -> String {
+            self.id.clone()
+        }
+        fn cache_expiry() -> usize {
+            3600
+        }
+    }
+
+    #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    struct Expired {
+        id: String,
+    }
+
+    impl Cacheable for Expired {
+        fn cache_path() -> &'static str {
+            "expired"
+        }
+        fn cache_id(&self) -> String {
+            self.id.clone()
+        }
+        fn cache_expiry() -> usize {
+            0
+        }
+    }
+
+    /// Shares the cache path of [Demo], but with a field that [Demo] lacks.
+    #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    struct Changed {
+        id: String,
+        added: usize,
+    }
+
+    impl Cacheable for Changed {
+        fn cache_path() -> &'static str {
+            "demo"
+        }
+        fn cache_id(&self) -> String {
+            self.id.clone()
+        }
+        fn cache_expiry() -> usize {
+            3600
+        }
+    }
+
+    /// [Changed], with its schema version in the cache key.
+    #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    struct Versioned {
+        id: String,
+        added: usize,
+    }
+
+    impl Cacheable for Versioned {
+        fn cache_path() -> &'static str {
+            "demo"
+        }
+        fn cache_id(&self) -> String {
+            self.id.clone()
+        }
+        fn cache_expiry() -> usize {
+            3600
+        }
+        fn cache_version() -> Option<u32> {
+            Some(2)
+        }
+    }
+
+    fn demo(id: &str) -> Demo {
+        Demo { id: id.to_string() }
+    }
+
+    #[test]
+    fn test_put_fetch_delete() {
+        tokio_test::block_on(async {
+            let cache = MemoryCache::default();
+            cache.put(&demo("a")).await.unwrap();
+            assert_eq!(cache.fetch::<Demo>("a").await.unwrap(), Some(demo("a")));
+            assert_eq!(cache.fetch::<Demo>("b").await.unwrap(), None);
+
+            assert!(cache.delete::<Demo>("a").await.unwrap());
+            assert_eq!(cache.fetch::<Demo>("a").await.unwrap(), None);
+            assert!(!cache.delete::<Demo>("a").await.unwrap());
+        })
+    }
+
+    #[test]
+    fn test_deserialization_error() {
+        tokio_test::block_on(async {
+            let cache = MemoryCache::default();
+            cache.put(&demo("a")).await.unwrap();
+            match cache.fetch::<Changed>("a").await.unwrap_err() {
+                DaoError::Deserialization {
+                    location,
+                    type_name,
+                    source,
+                } => {
+                    assert_eq!(location, "demo:a");
+                    assert!(type_name.ends_with("Changed"));
+                    assert!(source.to_string().contains("added"));
+                }
+                err => panic!("unexpected error: {:?}", err),
+            }
+        })
+    }
+
+    #[test]
+    fn test_versioned_keys() {
+        tokio_test::block_on(async {
+            let cache = MemoryCache::default();
+            cache.put(&demo("a")).await.unwrap();
+            assert_eq!(cache.fetch::<Versioned>("a").await.unwrap(), None);
+
+            let versioned = Versioned {
+                id: "a".into(),
+                added: 1,
+            };
+            cache.put(&versioned).await.unwrap();
+            assert_eq!(
+                cache.fetch::<Versioned>("a").await.unwrap(),
+                Some(versioned)
+            );
+            assert_eq!(cache.fetch::<Demo>("a").await.unwrap(), Some(demo("a")));
+        })
+    }
+
+    #[test]
+    fn test_expiry() {
+        tokio_test::block_on(async {
+            let cache = MemoryCache::default();
+            cache.put(&Expired { id: "a".into() }).await.unwrap();
+            assert_eq!(cache.fetch::<Expired>("a").await.unwrap(), None);
+            assert!(cache.is_empty());
+        })
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        tokio_test::block_on(async {
+            let cache = MemoryCache::new(2);
+            cache.put(&demo("a")).await.unwrap();
+            cache.put(&demo("b")).await.unwrap();
+            // Touch "a" so that "b" is the least recently used
+            cache.fetch::<Demo>("a").await.unwrap();
+            cache.put(&demo("c")).await.unwrap();
+
+            assert_eq!(cache.len(), 2);
+            assert!(cache.fetch::<Demo>("a").await.unwrap().is_some());
+            assert!(cache.fetch::<Demo>("b").await.unwrap().is_none());
+            assert!(cache.fetch::<Demo>("c").await.unwrap().is_some());
+        })
+    }
+}
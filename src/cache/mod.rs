@@ -1,7 +1,21 @@
 /// Cache abstraction layer
-/// The crate currently only supports Redis.  But extending to support other
-/// cache services is as simple as adding another target and  then updating the feature flags in
-/// [Cargo.toml](./Cargo.toml)
+/// Redis is the primary target, and is available with the `redis` feature.  Extending to support
+/// other cache services is as simple as implementing [CacheStore] for another target and then
+/// updating the feature flags in [Cargo.toml](./Cargo.toml)
+/// [MemoryCache] is always available, and keeps everything in process.
+/// `TieredCache` combines the two, with a local L1 in front of Redis.
+/// Values are encoded in their [CacheFormat](crate::CacheFormat) by the `cache_format` module.
+pub use cache_store::*;
+pub use memory_cache::*;
+#[cfg(feature = "redis")]
 pub use redis_cache::*;
+#[cfg(feature = "redis")]
+pub use tiered_cache::*;
 
+pub(crate) mod cache_format;
+pub mod cache_store;
+pub mod memory_cache;
+#[cfg(feature = "redis")]
 pub mod redis_cache;
+#[cfg(feature = "redis")]
+pub mod tiered_cache;
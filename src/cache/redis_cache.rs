@@ -1,14 +1,54 @@
-/// Cache implementation for Redis
-use std::sync::Arc;
+/// Cache implementation for Redis.
+/// Every operation goes through one multiplexed [ConnectionManager], which is shared by clones
+/// of the cache and reconnects by itself, so no connection is opened per call.
+use async_trait::async_trait;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use redis::{aio::ConnectionManager, AsyncCommands, Client, Value};
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::{Cacheable, DaoError, DaoResult, DataServicesConfig};
+use crate::{
+    cache::{
+        cache_format::{decode, encode},
+        cache_key, missing_key,
+    },
+    CacheStore, Cacheable, DaoError, DaoResult, DataServicesConfig,
+};
+
+/// Releases a lock only if it still holds the token it was taken with, so that a lock which
+/// expired and was taken by someone else is left alone.
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// An id that is unique across processes and across calls within this one.
+pub(crate) fn unique_id() -> String {
+    static IDS: AtomicUsize = AtomicUsize::new(0);
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{}-{}-{}",
+        std::process::id(),
+        started,
+        IDS.fetch_add(1, Ordering::Relaxed)
+    )
+}
 
 #[derive(Clone)]
 pub struct Cache {
     pub config: Arc<DataServicesConfig>,
+    /// Only used for connections that can't be shared, such as pub/sub
     pub client: Client,
     pub connection_manager: ConnectionManager,
 }
@@ -18,7 +58,7 @@ impl Cache {
         let client = Client::open(config.cache_uri.clone())
             .map_err(|_| DaoError::ServiceError("Redis: Failed to create client".to_string()))?;
 
-        let connection_manager = client.get_tokio_connection_manager().await.map_err(|_| {
+        let connection_manager = client.get_connection_manager().await.map_err(|_| {
             DaoError::ServiceError("Redis: Failed to create connection manager".to_string())
         })?;
 
@@ -29,53 +69,167 @@ impl Cache {
         })
     }
 
-    pub async fn put<T>(&self, value: &T) -> DaoResult<()>
+    /// Fetch the stored bytes for a cache key, without decoding them.
+    pub(crate) async fn fetch_raw(&self, cache_key: &str) -> DaoResult<Option<Vec<u8>>> {
+        let mut con = self.connection_manager.clone();
+        let cache_response = con.get(cache_key).await?;
+
+        match cache_response {
+            Value::Nil => Ok(None),
+            Value::Data(val) => Ok(Some(val)),
+            other => Err(DaoError::UnexpectedCacheValue {
+                key: cache_key.to_string(),
+                value: format!("{:?}", other),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStore for Cache {
+    async fn put<T>(&self, value: &T) -> DaoResult<()>
     where
-        T: Cacheable + Serialize,
+        T: Cacheable + Serialize + Send + Sync,
     {
-        let cache_key = format!("{}:{}", T::cache_path(), value.cache_id()).to_owned();
-        let mut con = self.client.get_async_connection().await?;
-        let data = serde_json::to_vec(value)?;
+        let cache_key = cache_key::<T>(&value.cache_id());
+        let mut con = self.connection_manager.clone();
+        let data = encode(value, &cache_key)?;
         redis::pipe()
             .atomic()
             .set(&cache_key, data)
             .expire(&cache_key, T::cache_expiry())
-            .query_async(&mut con)
+            .query_async::<_, ()>(&mut con)
             .await?;
         log::trace!("Cached: {}", &cache_key);
         Ok(())
     }
 
-    pub async fn fetch<T>(&self, id: &str) -> DaoResult<Option<T>>
+    async fn fetch<T>(&self, id: &str) -> DaoResult<Option<T>>
     where
         T: Clone + Cacheable + DeserializeOwned + Unpin + Send + Sync,
     {
-        let cache_key = format!("{}:{}", T::cache_path(), id).to_owned();
-        let mut con = self.client.get_async_connection().await?;
-        let cache_response = con.get(&cache_key).await?;
-
-        match cache_response {
-            Value::Nil => {
+        let cache_key = cache_key::<T>(id);
+        match self.fetch_raw(&cache_key).await? {
+            None => {
                 log::trace!("Item not in cache: {}", &cache_key);
                 Ok(None)
             }
-            Value::Data(val) => {
-                let result = serde_json::from_slice::<T>(&val)?;
+            Some(val) => {
+                let result = decode::<T>(&val, &cache_key)?;
                 log::trace!("Fetched from cache: {}", &cache_key);
-                Ok(Some(result.clone()))
+                Ok(Some(result))
             }
-            _ => Err(DaoError::GeneralError.into()),
         }
     }
 
-    pub async fn delete<T>(&self, id: &str) -> DaoResult<()>
+    async fn delete<T>(&self, id: &str) -> DaoResult<bool>
     where
         T: Cacheable,
     {
-        let cache_key = format!("{}:{}", T::cache_path(), id).to_owned();
-        let mut con = self.client.get_async_connection().await?;
-        con.del(&cache_key).await?;
+        let cache_key = cache_key::<T>(id);
+        let mut con = self.connection_manager.clone();
+        let deleted = con.del::<_, usize>(&cache_key).await?;
         log::trace!("Deleted from cache: {}", &cache_key);
+        Ok(deleted > 0)
+    }
+
+    async fn put_many<T>(&self, values: &[T]) -> DaoResult<()>
+    where
+        T: Cacheable + Serialize + Send + Sync,
+    {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for value in values {
+            let cache_key = cache_key::<T>(&value.cache_id());
+            let data = encode(value, &cache_key)?;
+            pipe.set_ex(&cache_key, data, T::cache_expiry()).ignore();
+        }
+        let mut con = self.connection_manager.clone();
+        pipe.query_async::<_, ()>(&mut con).await?;
+        log::trace!("Cached {} of {}", values.len(), T::cache_path());
+        Ok(())
+    }
+
+    async fn delete_many<T>(&self, ids: &[&str]) -> DaoResult<()>
+    where
+        T: Cacheable,
+    {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let cache_keys = ids.iter().map(|id| cache_key::<T>(id)).collect::<Vec<_>>();
+        let mut con = self.connection_manager.clone();
+        con.del::<_, ()>(&cache_keys).await?;
+        log::trace!("Deleted {} of {} from cache", ids.len(), T::cache_path());
+        Ok(())
+    }
+
+    async fn put_missing<T>(&self, id: &str) -> DaoResult<()>
+    where
+        T: Cacheable,
+    {
+        // Redis refuses an expiry of 0, which caches nothing anyway
+        if let Some(expiry) = T::cache_miss_expiry().filter(|expiry| *expiry > 0) {
+            let mut con = self.connection_manager.clone();
+            con.set_ex::<_, _, ()>(missing_key::<T>(id), 1, expiry)
+                .await?;
+            log::trace!("Cached miss: {}", cache_key::<T>(id));
+        }
+        Ok(())
+    }
+
+    async fn is_missing<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Cacheable,
+    {
+        if T::cache_miss_expiry().is_none() {
+            return Ok(false);
+        }
+        let mut con = self.connection_manager.clone();
+        Ok(con.exists::<_, bool>(missing_key::<T>(id)).await?)
+    }
+
+    async fn delete_missing<T>(&self, id: &str) -> DaoResult<()>
+    where
+        T: Cacheable,
+    {
+        if T::cache_miss_expiry().is_some() {
+            let mut con = self.connection_manager.clone();
+            con.del::<_, ()>(missing_key::<T>(id)).await?;
+        }
+        Ok(())
+    }
+
+    async fn try_lock(&self, key: &str, ttl: Duration) -> DaoResult<Option<String>> {
+        let token = unique_id();
+        let mut con = self.connection_manager.clone();
+        let response = redis::cmd("SET")
+            .arg(key)
+            .arg(&token)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis().max(1) as u64)
+            .query_async::<_, Value>(&mut con)
+            .await?;
+        match response {
+            Value::Nil => Ok(None),
+            _ => {
+                log::trace!("Locked: {}", key);
+                Ok(Some(token))
+            }
+        }
+    }
+
+    async fn unlock(&self, key: &str, token: &str) -> DaoResult<()> {
+        let mut con = self.connection_manager.clone();
+        redis::Script::new(UNLOCK_SCRIPT)
+            .key(key)
+            .arg(token)
+            .invoke_async::<_, ()>(&mut con)
+            .await?;
+        log::trace!("Unlocked: {}", key);
         Ok(())
     }
 }
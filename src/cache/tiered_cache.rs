@@ -0,0 +1,295 @@
+/// Two tier cache: a small in process [MemoryCache] (L1) in front of the Redis [Cache] (L2).
+/// Reads are served from L1 when possible, falling back to Redis.  Every write goes to both tiers,
+/// and is then announced on a Redis pub/sub channel so that the other service instances drop their
+/// now stale L1 copy.
+///
+/// Cached misses are only kept in Redis, so every instance sees them cleared at once.
+///
+/// Pub/sub delivery is best effort, so keep the L1 expiry short with [MemoryCache::with_max_expiry].
+/// A write whose announcement fails still succeeds, and the failure is logged.
+///
+/// The invalidation listener runs until the last clone of the cache is dropped.
+use async_trait::async_trait;
+use std::{sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use redis::AsyncCommands;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{
+    cache::{cache_format::decode, cache_key, redis_cache::unique_id},
+    Cache, CacheStore, Cacheable, DaoResult, MemoryCache,
+};
+
+/// Redis channel that L1 invalidations are published on.
+pub const TIERED_CACHE_CHANNEL: &str = "swanky_persist:invalidate";
+
+/// How long the invalidation listener waits before reconnecting to Redis.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct TieredCache {
+    /// The in process L1 cache
+    pub local: MemoryCache,
+    /// The shared Redis L2 cache
+    pub remote: Cache,
+    /// Identifies this instance, so that it can ignore its own invalidations
+    instance_id: String,
+    /// Shared by clones, so the listener stops along with the last of them
+    _listener: Arc<Listener>,
+}
+
+/// Aborts the invalidation listener task when dropped.
+struct Listener(JoinHandle<()>);
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl TieredCache {
+    /// Put `local` in front of `remote`, and start listening for invalidations from other instances.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn new(remote: Cache, local: MemoryCache) -> TieredCache {
+        let instance_id = unique_id();
+        let listener = listen(&remote, &local, &instance_id);
+        Self {
+            local,
+            remote,
+            instance_id,
+            _listener: Arc::new(listener),
+        }
+    }
+
+    /// Tell the other instances to drop their L1 copy of a cache key.
+    async fn publish(&self, cache_key: &str) {
+        let mut con = self.remote.connection_manager.clone();
+        let published = con
+            .publish::<_, _, ()>(
+                TIERED_CACHE_CHANNEL,
+                format!("{} {}", self.instance_id, cache_key),
+            )
+            .await;
+        if let Err(err) = published {
+            log::warn!("Failed to announce invalidation of {}: {}", cache_key, err);
+        }
+    }
+
+    /// Tell the other instances to drop their L1 copies of several cache keys, in one round trip.
+    async fn publish_many(&self, cache_keys: impl Iterator<Item = String>) {
+        let mut pipe = redis::pipe();
+        for cache_key in cache_keys {
+            pipe.publish(
+                TIERED_CACHE_CHANNEL,
+                format!("{} {}", self.instance_id, cache_key),
+            )
+            .ignore();
+        }
+        let mut con = self.remote.connection_manager.clone();
+        if let Err(err) = pipe.query_async::<_, ()>(&mut con).await {
+            log::warn!("Failed to announce invalidations: {}", err);
+        }
+    }
+}
+
+/// Spawn the task that drops L1 entries invalidated by other instances.
+fn listen(remote: &Cache, local: &MemoryCache, instance_id: &str) -> Listener {
+    let client = remote.client.clone();
+    let local = local.clone();
+    let instance_id = instance_id.to_string();
+
+    Listener(tokio::spawn(async move {
+        loop {
+            match client.get_async_connection().await {
+                Ok(con) => {
+                    let mut pubsub = con.into_pubsub();
+                    if let Err(err) = pubsub.subscribe(TIERED_CACHE_CHANNEL).await {
+                        log::error!("Failed to subscribe to {}: {}", TIERED_CACHE_CHANNEL, err);
+                    } else {
+                        let mut messages = pubsub.on_message();
+                        while let Some(msg) = messages.next().await {
+                            if let Ok(payload) = msg.get_payload::<String>() {
+                                invalidate(&local, &instance_id, &payload);
+                            }
+                        }
+                    }
+                    log::warn!("Lost subscription to {}", TIERED_CACHE_CHANNEL);
+                }
+                Err(err) => {
+                    log::error!("Failed to connect for {}: {}", TIERED_CACHE_CHANNEL, err);
+                }
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }))
+}
+
+/// Apply an invalidation message of the form `{instance_id} {cache_key}` to the local cache.
+/// Messages sent by this instance are ignored.
+fn invalidate(local: &MemoryCache, instance_id: &str, payload: &str) {
+    if let Some((sender, cache_key)) = payload.split_once(' ') {
+        if sender != instance_id && local.invalidate(cache_key).unwrap_or(false) {
+            log::trace!("Invalidated L1: {}", cache_key);
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStore for TieredCache {
+    async fn put<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Cacheable + Serialize + Send + Sync,
+    {
+        self.remote.put(value).await?;
+        self.local.put(value).await?;
+        self.publish(&cache_key::<T>(&value.cache_id())).await;
+        Ok(())
+    }
+
+    async fn fetch<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + Cacheable + DeserializeOwned + Unpin + Send + Sync,
+    {
+        if let Some(result) = self.local.fetch::<T>(id).await? {
+            return Ok(Some(result));
+        }
+
+        let cache_key = cache_key::<T>(id);
+        match self.remote.fetch_raw(&cache_key).await? {
+            Some(data) => {
+                let result = decode::<T>(&data, &cache_key)?;
+                self.local.put_raw(cache_key, data, T::cache_expiry())?;
+                Ok(Some(result))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn delete<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Cacheable,
+    {
+        let remote = self.remote.delete::<T>(id).await?;
+        let local = self.local.delete::<T>(id).await?;
+        self.publish(&cache_key::<T>(id)).await;
+        Ok(remote || local)
+    }
+
+    async fn put_many<T>(&self, values: &[T]) -> DaoResult<()>
+    where
+        T: Cacheable + Serialize + Send + Sync,
+    {
+        if values.is_empty() {
+            return Ok(());
+        }
+        self.remote.put_many(values).await?;
+        self.local.put_many(values).await?;
+        self.publish_many(values.iter().map(|value| cache_key::<T>(&value.cache_id())))
+            .await;
+        Ok(())
+    }
+
+    async fn delete_many<T>(&self, ids: &[&str]) -> DaoResult<()>
+    where
+        T: Cacheable,
+    {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        self.remote.delete_many::<T>(ids).await?;
+        self.local.delete_many::<T>(ids).await?;
+        self.publish_many(ids.iter().map(|id| cache_key::<T>(id)))
+            .await;
+        Ok(())
+    }
+
+    async fn put_missing<T>(&self, id: &str) -> DaoResult<()>
+    where
+        T: Cacheable,
+    {
+        self.remote.put_missing::<T>(id).await
+    }
+
+    async fn is_missing<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Cacheable,
+    {
+        self.remote.is_missing::<T>(id).await
+    }
+
+    async fn delete_missing<T>(&self, id: &str) -> DaoResult<()>
+    where
+        T: Cacheable,
+    {
+        self.remote.delete_missing::<T>(id).await
+    }
+
+    async fn try_lock(&self, key: &str, ttl: Duration) -> DaoResult<Option<String>> {
+        self.remote.try_lock(key, ttl).await
+    }
+
+    async fn unlock(&self, key: &str, token: &str) -> DaoResult<()> {
+        self.remote.unlock(key, token).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    struct Demo {
+        id: String,
+    }
+
+    impl Cacheable for Demo {
+        fn cache_path() -> &'static str {
+            "demo"
+        }
+        fn cache_id(&self) -> String {
+            self.id.clone()
+        }
+        fn cache_expiry() -> usize {
+            3600
+        }
+    }
+
+    #[test]
+    fn test_invalidate() {
+        tokio_test::block_on(async {
+            let local = MemoryCache::default();
+            local.put(&Demo { id: "a".into() }).await.unwrap();
+
+            // Our own message leaves the entry alone
+            invalidate(&local, "me", "me demo:a");
+            assert!(local.fetch::<Demo>("a").await.unwrap().is_some());
+
+            invalidate(&local, "me", "malformed");
+            assert!(local.fetch::<Demo>("a").await.unwrap().is_some());
+
+            invalidate(&local, "me", "them demo:a");
+            assert!(local.fetch::<Demo>("a").await.unwrap().is_none());
+        })
+    }
+
+    #[test]
+    fn test_listener_stops_with_last_clone() {
+        tokio_test::block_on(async {
+            let (alive, stopped) = tokio::sync::oneshot::channel::<()>();
+            let listener = Arc::new(Listener(tokio::spawn(async move {
+                let _alive = alive;
+                futures::future::pending::<()>().await
+            })));
+            let clone = listener.clone();
+            drop(listener);
+            assert!(!clone.0.is_finished());
+
+            drop(clone);
+            // The sender is dropped once the task is aborted
+            assert!(stopped.await.is_err());
+        })
+    }
+}
@@ -2,29 +2,90 @@
 use thiserror::Error;
 
 /// Just re-wrapping for ease of use locally.
-pub type DaoResult<T> = Result<T, Box<dyn std::error::Error>>;
+pub type DaoResult<T> = Result<T, DaoError>;
+
+/// The underlying cause of a [DaoError::Serialization] or [DaoError::Deserialization].
+pub type BoxedError = Box<dyn std::error::Error + Send + Sync>;
 
 /// Error management, using [thiserror]
+///
+/// `DaoError` is `Send + Sync + 'static`, so it can be returned from spawned tasks and web handlers.
 #[derive(Error, Debug)]
 pub enum DaoError {
     #[error("Service error: {0}")]
     ServiceError(String),
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+    /// A value could not be encoded for storage.
+    #[error("Failed to serialize {type_name} for {location}: {source}")]
+    Serialization {
+        /// The cache key or collection the value was headed for
+        location: String,
+        type_name: &'static str,
+        #[source]
+        source: BoxedError,
+    },
+    /// A stored value could not be decoded.
+    #[error("Failed to deserialize {type_name} from {location}: {source}")]
+    Deserialization {
+        /// The cache key or collection the value came from
+        location: String,
+        type_name: &'static str,
+        #[source]
+        source: BoxedError,
+    },
+    #[cfg(feature = "mongodb")]
     #[error("mongodb error: {0}")]
     DatabaseError(#[from] mongodb::error::Error),
+    #[cfg(feature = "mongodb")]
     #[error("could not access field in document: {0}")]
     MongoDataError(#[from] mongodb::bson::document::ValueAccessError),
+    #[cfg(feature = "redis")]
     #[error("Cache error: {0}")]
     CacheError(#[from] redis::RedisError),
+    #[error("Unexpected value in cache for {key}: {value}")]
+    UnexpectedCacheValue { key: String, value: String },
+    #[cfg(feature = "sqlite")]
+    #[error("sqlite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
     #[error("A value with this id already exists: {0}")]
     IdExists(String),
+    #[error("Invalid page token: {0}")]
+    InvalidPageToken(String),
     #[error("Not found error")]
     NotFound,
     #[error("General error")]
     GeneralError,
 }
 
-impl From<serde_json::Error> for DaoError {
-    fn from(_source: serde_json::Error) -> Self {
-        Self::GeneralError
+impl DaoError {
+    /// Failed to encode a `T` headed for `location`.
+    pub fn serialization<T: ?Sized>(
+        location: impl Into<String>,
+        source: impl Into<BoxedError>,
+    ) -> Self {
+        Self::Serialization {
+            location: location.into(),
+            type_name: std::any::type_name::<T>(),
+            source: source.into(),
+        }
+    }
+
+    /// Failed to decode a `T` read from `location`.
+    pub fn deserialization<T: ?Sized>(
+        location: impl Into<String>,
+        source: impl Into<BoxedError>,
+    ) -> Self {
+        Self::Deserialization {
+            location: location.into(),
+            type_name: std::any::type_name::<T>(),
+            source: source.into(),
+        }
     }
 }
+
+// Fail the build if a variant ever makes DaoError unsafe to send between threads.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<DaoError>();
+};
@@ -1,31 +1,77 @@
 /// Persistance layer.  This layer doesn't much care about what fulfills the Cache and DB layers.
-/// One expection is that MongoDB requires anythiung added to a Document to implement
-/// Into<mongodb::bson::Bson>. While you don't have to implement that for your structs, it does have
-/// to be declared as a trrait on the `modify` methods.  If anyone can figure out how I can
-/// abstract to just use serde traits, that would be awesome!
-use std::sync::Arc;
+/// The database and cache are reached through the [PersistStore] and [CacheStore] traits, so
+/// values only need to implement the serde traits.  A bare `DataServices` means MongoDB and Redis,
+/// and is only available when both their features are enabled, so that enabling a feature never
+/// changes what an existing `DataServices` is.  [MemoryDataServices] keeps everything in process,
+/// and any other stores can be provided with [DataServices::with_stores].
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use serde::{de::DeserializeOwned, Serialize};
 
-use super::{Cache, Cacheable, DaoResult, DataServicesConfig, Persistable, DB};
+use super::{
+    cache::cache_key, single_flight::SingleFlight, BulkReport, CacheStore, Cacheable, DaoError,
+    DaoResult, DaoStream, DataServicesConfig, FetchOptions, Filter, Page, PersistStore,
+    Persistable, Update,
+};
 
+/// How often a cache miss waiting on another process's load checks the cache again.
+const LOAD_LOCK_POLL: Duration = Duration::from_millis(25);
+
+/// Data services kept entirely in process, with [MemoryDB](crate::MemoryDB) and
+/// [MemoryCache](crate::MemoryCache).
+pub type MemoryDataServices = DataServices<crate::MemoryDB, crate::MemoryCache>;
+
+#[cfg(all(feature = "mongodb", feature = "redis"))]
 #[derive(Clone)]
-pub struct DataServices {
+pub struct DataServices<D = crate::DB, C = crate::Cache> {
     pub config: Arc<DataServicesConfig>,
-    /// Represents the Redis cache client
-    pub cache: Cache,
-    pub db: DB,
+    /// Represents the cache client
+    pub cache: C,
+    /// Represents the persistence store
+    pub db: D,
+    /// Cache keys being loaded from the db by this process
+    loads: SingleFlight,
 }
 
-#[allow(dead_code)]
+/// Without both the `mongodb` and `redis` features there is no default store, so the stores
+/// must always be named.
+#[cfg(not(all(feature = "mongodb", feature = "redis")))]
+#[derive(Clone)]
+pub struct DataServices<D, C> {
+    pub config: Arc<DataServicesConfig>,
+    /// Represents the cache client
+    pub cache: C,
+    /// Represents the persistence store
+    pub db: D,
+    /// Cache keys being loaded from the db by this process
+    loads: SingleFlight,
+}
+
+#[cfg(all(feature = "mongodb", feature = "redis"))]
 impl DataServices {
     /// Establishes the client connections to the database and cache.
     ///
     /// This should be called only once in the crate main.
     pub async fn new(config: Arc<DataServicesConfig>) -> DaoResult<DataServices> {
-        let cache = Cache::new(config.clone()).await?;
-        let db = DB::new(config.clone()).await?;
-        Ok(DataServices { config, cache, db })
+        let cache = crate::Cache::new(config.clone()).await?;
+        let db = crate::DB::new(config.clone()).await?;
+        Ok(DataServices::with_stores(config, cache, db))
+    }
+}
+
+#[allow(dead_code)]
+impl<D: PersistStore, C: CacheStore> DataServices<D, C> {
+    /// Build the services from an already established cache and persistence store.
+    pub fn with_stores(config: Arc<DataServicesConfig>, cache: C, db: D) -> Self {
+        DataServices {
+            config,
+            cache,
+            db,
+            loads: SingleFlight::default(),
+        }
     }
 
     /// Add an object instance to the DB
@@ -58,6 +104,7 @@ impl DataServices {
     {
         let result = self.db.add(value).await?;
         self.cache.put(&result).await?;
+        self.cache.delete_missing::<T>(&result.cache_id()).await?;
         Ok(result)
     }
 
@@ -74,78 +121,944 @@ impl DataServices {
     pub async fn fetch<T, K>(&self, key: Option<&str>, value: Option<K>) -> DaoResult<Vec<T>>
     where
         T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
-        K: Serialize,
+        K: Serialize + Send + Sync,
     {
         match self.db.fetch::<T, K>(key, value).await? {
             Some(v) => Ok(v),
             None => Ok(Vec::<T>::new()),
         }
     }
+
+    /// Fetch every object matching a [Filter]
+    pub async fn fetch_where<T>(&self, filter: &Filter) -> DaoResult<Vec<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        match self.db.fetch_where::<T>(filter).await? {
+            Some(v) => Ok(v),
+            None => Ok(Vec::<T>::new()),
+        }
+    }
+
+    /// Fetch the objects matching a [Filter], sorted, skipped and limited by [FetchOptions]
+    pub async fn fetch_with<T>(&self, filter: &Filter, options: &FetchOptions) -> DaoResult<Vec<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        match self.db.fetch_with::<T>(filter, options).await? {
+            Some(v) => Ok(v),
+            None => Ok(Vec::<T>::new()),
+        }
+    }
+
+    /// Count the objects matching a [Filter]
+    pub async fn count<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        self.db.count::<T>(filter).await
+    }
+
+    /// Check whether an object is stored, without fetching it
+    pub async fn exists<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Persistable,
+    {
+        self.db.exists::<T>(id).await
+    }
+
+    /// The distinct values of a field across the objects matching a [Filter]
+    pub async fn distinct<T, V>(&self, field: &str, filter: &Filter) -> DaoResult<Vec<V>>
+    where
+        T: Persistable,
+        V: DeserializeOwned + Send,
+    {
+        self.db.distinct::<T, V>(field, filter).await
+    }
+
+    /// Stream the objects matching a [Filter], without holding them all in memory when the
+    /// store supports it.  See [PersistStore::fetch_stream].
+    pub async fn fetch_stream<T>(
+        &self,
+        filter: &Filter,
+        options: &FetchOptions,
+    ) -> DaoResult<DaoStream<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable + 'static,
+    {
+        self.db.fetch_stream::<T>(filter, options).await
+    }
+
+    /// Fetch one page of the objects matching a [Filter].  Pass the returned [Page::next] token
+    /// back in to fetch the following page.
+    pub async fn fetch_page<T>(
+        &self,
+        filter: &Filter,
+        options: &FetchOptions,
+        token: Option<&str>,
+    ) -> DaoResult<Page<T>>
+    where
+        T: Clone + DeserializeOwned + Serialize + Unpin + Send + Sync + Persistable,
+    {
+        self.db.fetch_page::<T>(filter, options, token).await
+    }
+
     /// Fetch a possibly cached object.
     /// Looks in cache first.  If not found, it looks in DB.  If found, it adds t
     /// the cache.
+    ///
+    /// Concurrent misses for the same object in this process are coalesced, so only one of them
+    /// reads the db while the rest wait for and share its result.  With
+    /// [DataServicesConfig::cache_load_lock] set, misses in other processes sharing the cache are
+    /// coalesced too.
+    ///
+    /// Types with a [Cacheable::cache_miss_expiry] also cache that an id has no object, until
+    /// the miss expires or an object is stored under the id by one of the `_cached` writes.
+    ///
+    /// With [DataServicesConfig::cache_self_heal] set, a cached object that no longer decodes is
+    /// deleted and reloaded from the db rather than failing the fetch.
     pub async fn fetch_by_id_cached<T>(&self, id: &str) -> DaoResult<Option<T>>
     where
         T: Clone + Persistable + Cacheable + DeserializeOwned + Serialize + Unpin + Send + Sync,
     {
-        match self.cache.fetch::<T>(id).await? {
-            Some(t) => Ok(Some(t)),
-            None => {
-                // The item is not in cache.  Look in the db.
-                let result = self.db.fetch_by_id::<T>(id).await?;
-                match result {
-                    Some(t) => {
-                        // Found the object in the db.  So cache it and then return it
-                        self.cache.put(&t).await?;
-                        Ok(Some(t))
-                    }
-                    None => Ok(None),
+        if let Some(cached) = self.cached::<T>(id).await? {
+            return Ok(cached);
+        }
+
+        // The item is not in cache.  Share the result of anyone else in this process loading it,
+        // or look again before going to the db.
+        let key = cache_key::<T>(id);
+        self.loads
+            .run(&key, || async {
+                if let Some(cached) = self.cached::<T>(id).await? {
+                    return Ok(cached);
                 }
+                match self.config.cache_load_lock {
+                    Some(ttl) => self.load_locked::<T>(id, &key, ttl).await,
+                    None => self.load::<T>(id).await,
+                }
+            })
+            .await
+    }
+
+    /// Look an object up in the cache.  Returns `Some(None)` if the cache knows there is no
+    /// object with the id, and `None` if the cache can't say.
+    async fn cached<T>(&self, id: &str) -> DaoResult<Option<Option<T>>>
+    where
+        T: Clone + Cacheable + DeserializeOwned + Unpin + Send + Sync,
+    {
+        match self.cache.fetch::<T>(id).await {
+            Ok(Some(t)) => return Ok(Some(Some(t))),
+            Ok(None) => {}
+            Err(err @ DaoError::Deserialization { .. }) if self.config.cache_self_heal => {
+                log::warn!("Dropping undecodable cache entry: {}", err);
+                self.cache.delete::<T>(id).await?;
             }
+            Err(err) => return Err(err),
+        }
+        match self.cache.is_missing::<T>(id).await? {
+            true => Ok(Some(None)),
+            false => Ok(None),
         }
     }
 
-    /// Update a persisted object.
-    pub async fn update<T, K>(&self, id: &str, key: &str, value: K) -> DaoResult<Option<T>>
+    /// Fetch an object from the db, and cache it, or that it is missing.
+    async fn load<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + Persistable + Cacheable + DeserializeOwned + Serialize + Unpin + Send + Sync,
+    {
+        let result = self.db.fetch_by_id::<T>(id).await?;
+        match &result {
+            // Found the object in the db.  So cache it and then return it
+            Some(t) => self.cache.put(t).await?,
+            None if T::cache_miss_expiry().is_some() => {
+                self.cache.put_missing::<T>(id).await?;
+                // An object added since the read above may have cleared its miss before it was
+                // cached, so look once more and undo the miss if one turns up.
+                if let Some(t) = self.db.fetch_by_id::<T>(id).await? {
+                    self.cache.delete_missing::<T>(id).await?;
+                    self.cache.put(&t).await?;
+                    return Ok(Some(t));
+                }
+            }
+            None => {}
+        }
+        Ok(result)
+    }
+
+    /// [DataServices::load] under a lock in the cache, so only one process loads the object.
+    /// The others poll the cache until it is filled, or load it themselves if the lock holder
+    /// takes longer than `ttl`.
+    async fn load_locked<T>(&self, id: &str, key: &str, ttl: Duration) -> DaoResult<Option<T>>
+    where
+        T: Clone + Persistable + Cacheable + DeserializeOwned + Serialize + Unpin + Send + Sync,
+    {
+        let lock = format!("swanky_persist:lock:{}", key);
+        let deadline = Instant::now() + ttl;
+        loop {
+            if let Some(token) = self.cache.try_lock(&lock, ttl).await? {
+                let result = self.load::<T>(id).await;
+                self.cache.unlock(&lock, &token).await?;
+                return result;
+            }
+            if Instant::now() >= deadline {
+                log::warn!("Timed out waiting for another process to load {}", key);
+                return self.load::<T>(id).await;
+            }
+            tokio::time::sleep(LOAD_LOCK_POLL).await;
+            if let Some(cached) = self.cached::<T>(id).await? {
+                return Ok(cached);
+            }
+        }
+    }
+
+    /// Update a persisted object.  Fails with [DaoError::NotFound](crate::DaoError::NotFound) if
+    /// there is no object with the id.
+    pub async fn update<T, K>(&self, id: &str, key: &str, value: K) -> DaoResult<T>
     where
         T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
-        K: Clone + Serialize + Into<mongodb::bson::Bson>, // mongodb::bson::Bson: std::convert::From<K>,
+        K: Clone + Serialize + Send + Sync,
     {
         self.db.update::<T, K>(id, key, value).await
     }
 
     /// Update a persisted object, and refresh the cache.
     /// We just re-put the object in the cache, so that expiry times are updated appropriately.
-    pub async fn update_cached<T, K>(&self, id: &str, key: &str, value: K) -> DaoResult<Option<T>>
+    pub async fn update_cached<T, K>(&self, id: &str, key: &str, value: K) -> DaoResult<T>
+    where
+        T: Clone + Persistable + Cacheable + DeserializeOwned + Serialize + Unpin + Send + Sync,
+        K: Clone + Serialize + Send + Sync,
+    {
+        self.update_with_cached::<T>(id, Update::new().set(key, value))
+            .await
+    }
+
+    /// Apply several field operations to a persisted object in one atomic step.  Fails with
+    /// [DaoError::NotFound](crate::DaoError::NotFound) if there is no object with the id.
+    pub async fn update_with<T>(&self, id: &str, update: Update) -> DaoResult<T>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        self.db.update_with::<T>(id, update).await
+    }
+
+    /// Apply several field operations to a persisted object, and re-cache the updated object.
+    pub async fn update_with_cached<T>(&self, id: &str, update: Update) -> DaoResult<T>
     where
         T: Clone + Persistable + Cacheable + DeserializeOwned + Serialize + Unpin + Send + Sync,
-        K: Clone + Serialize + Into<mongodb::bson::Bson>,
     {
-        match self.db.update::<T, K>(id, key, value).await? {
-            Some(object) => {
-                self.cache.put::<T>(&object).await?;
-                Ok(Some(object))
+        let object = match self.db.update_with::<T>(id, update).await {
+            Ok(object) => object,
+            // Some stores keep an update whose result can't be decoded, so drop the stale entry
+            Err(err @ DaoError::Deserialization { .. }) => {
+                self.cache.delete::<T>(id).await?;
+                return Err(err);
             }
-            None => Ok(None),
+            Err(err) => return Err(err),
+        };
+        self.cache.put::<T>(&object).await?;
+        // The update may have moved the object off its old id, and onto one that was missing
+        if object.cache_id() != id {
+            self.cache.delete::<T>(id).await?;
         }
+        self.cache.delete_missing::<T>(&object.cache_id()).await?;
+        Ok(object)
+    }
+
+    /// Overwrite a persisted object with `value`.  Fails with
+    /// [DaoError::NotFound](crate::DaoError::NotFound) if it isn't stored yet.
+    pub async fn replace<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        self.db.replace::<T>(value).await
+    }
+
+    /// Overwrite a persisted object with `value`, and re-cache it.
+    pub async fn replace_cached<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable + Cacheable,
+    {
+        self.db.replace::<T>(value).await?;
+        self.cache.put::<T>(value).await?;
+        self.cache.delete_missing::<T>(&value.cache_id()).await
+    }
+
+    /// Store `value`, whether or not it is already persisted.
+    pub async fn upsert<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        self.db.upsert::<T>(value).await
+    }
+
+    /// Store `value`, whether or not it is already persisted, and cache it.
+    pub async fn upsert_cached<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable + Cacheable,
+    {
+        self.db.upsert::<T>(value).await?;
+        self.cache.put::<T>(value).await?;
+        self.cache.delete_missing::<T>(&value.cache_id()).await
     }
 
     /// Delete an object from the db.
     /// Note, if you cached the object, and are calling this, your cachee will not match the db. use [DataServices::delete_cached] instead.
-    pub async fn delete<T>(&self, id: &str) -> DaoResult<()>
+    ///
+    /// Returns whether there was an object to delete.  With
+    /// [DataServicesConfig::strict_deletes] set, a missing object is an error instead.
+    pub async fn delete<T>(&self, id: &str) -> DaoResult<bool>
     where
         T: Persistable,
     {
-        self.db.delete::<T>(id).await
+        let deleted = self.db.delete::<T>(id).await?;
+        self.strict(deleted)
     }
 
-    /// Delete an object from both the db and the cache.
-    pub async fn delete_cached<T>(&self, id: &str) -> DaoResult<()>
+    /// Delete an object from both the db and the cache.  Returns whether the db had an object
+    /// to delete, following [DataServicesConfig::strict_deletes] like [DataServices::delete].
+    pub async fn delete_cached<T>(&self, id: &str) -> DaoResult<bool>
     where
         T: Persistable + Cacheable,
     {
-        self.db.delete::<T>(id).await?;
+        let deleted = self.db.delete::<T>(id).await?;
         self.cache.delete::<T>(id).await?;
-        Ok(())
+        self.strict(deleted)
+    }
+
+    /// Delete an object from the db and return it, following
+    /// [DataServicesConfig::strict_deletes] like [DataServices::delete].
+    pub async fn take<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        let object = self.db.take::<T>(id).await?;
+        self.strict(object.is_some())?;
+        Ok(object)
+    }
+
+    /// Delete an object from both the db and the cache, and return it.
+    pub async fn take_cached<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable + Cacheable,
+    {
+        let object = self.db.take::<T>(id).await?;
+        self.cache.delete::<T>(id).await?;
+        self.strict(object.is_some())?;
+        Ok(object)
+    }
+
+    /// Apply [DataServicesConfig::strict_deletes] to the outcome of a delete.
+    fn strict(&self, deleted: bool) -> DaoResult<bool> {
+        match deleted || !self.config.strict_deletes {
+            true => Ok(deleted),
+            false => Err(DaoError::NotFound),
+        }
+    }
+
+    /// Add several objects to the db in one batch.  Objects that can't be added are listed in
+    /// the report, and don't stop the rest.
+    pub async fn add_many<T>(&self, values: &[T]) -> DaoResult<BulkReport>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        self.db.add_many::<T>(values).await
+    }
+
+    /// Add several objects to the db in one batch, and cache the ones that were added.
+    pub async fn add_many_cached<T>(&self, values: &[T]) -> DaoResult<BulkReport>
+    where
+        T: Clone + Serialize + Send + Sync + Persistable + Cacheable,
+    {
+        let report = self.db.add_many::<T>(values).await?;
+        let added = values
+            .iter()
+            .zip(&report.results)
+            .filter(|(_, (_, result))| result.is_ok())
+            .map(|(value, _)| value.clone())
+            .collect::<Vec<T>>();
+        self.cache.put_many::<T>(&added).await?;
+        for value in &added {
+            self.cache.delete_missing::<T>(&value.cache_id()).await?;
+        }
+        Ok(report)
+    }
+
+    /// Apply an [Update] to every object matching a [Filter], returning how many matched.
+    pub async fn update_many<T>(&self, filter: &Filter, update: Update) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        self.db.update_many::<T>(filter, update).await
+    }
+
+    /// Apply an [Update] to every object matching a [Filter], and drop their cached copies so
+    /// that the next cached fetch reads the updated objects.
+    /// Objects that only start matching while this runs are left alone, as if they had changed
+    /// just after it.
+    pub async fn update_many_cached<T>(&self, filter: &Filter, update: Update) -> DaoResult<u64>
+    where
+        T: Persistable + Cacheable,
+    {
+        let (filter, ids) = self.pin_matches::<T>(filter).await?;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        // An update that sets the id moves objects onto it, so it is no longer missing
+        let new_id = match update.set_value(T::collection_id_field()) {
+            Some(serde_json::Value::String(id)) => Some(id.clone()),
+            Some(other) => Some(other.to_string()),
+            None => None,
+        };
+        let count = self.db.update_many::<T>(&filter, update).await?;
+        self.cache
+            .delete_many::<T>(&ids.iter().map(String::as_str).collect::<Vec<&str>>())
+            .await?;
+        for id in ids.iter().chain(&new_id) {
+            self.cache.delete_missing::<T>(id).await?;
+        }
+        Ok(count)
+    }
+
+    /// Delete several objects from the db in one batch.  Ids with nothing stored are listed in
+    /// the report.
+    pub async fn delete_many<T>(&self, ids: &[&str]) -> DaoResult<BulkReport>
+    where
+        T: Persistable,
+    {
+        self.db.delete_many::<T>(ids).await
+    }
+
+    /// Delete several objects from both the db and the cache.
+    pub async fn delete_many_cached<T>(&self, ids: &[&str]) -> DaoResult<BulkReport>
+    where
+        T: Persistable + Cacheable,
+    {
+        let report = self.db.delete_many::<T>(ids).await?;
+        self.cache.delete_many::<T>(ids).await?;
+        Ok(report)
+    }
+
+    /// Delete every object matching a [Filter], returning how many were deleted.
+    pub async fn delete_where<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        self.db.delete_where::<T>(filter).await
+    }
+
+    /// Delete every object matching a [Filter] from both the db and the cache.
+    /// Objects that only start matching while this runs are left alone, as if they had changed
+    /// just after it.
+    pub async fn delete_where_cached<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable + Cacheable,
+    {
+        let (filter, ids) = self.pin_matches::<T>(filter).await?;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let count = self.db.delete_where::<T>(&filter).await?;
+        self.cache
+            .delete_many::<T>(&ids.iter().map(String::as_str).collect::<Vec<&str>>())
+            .await?;
+        Ok(count)
+    }
+
+    /// Find the objects matching a [Filter], and narrow the filter down to just them.  A write
+    /// through the narrowed filter can't touch an object that matched too late to be seen here,
+    /// so invalidating the returned ids, as [Persistable::collection_id] reports them, is enough.
+    async fn pin_matches<T>(&self, filter: &Filter) -> DaoResult<(Filter, Vec<String>)>
+    where
+        T: Persistable,
+    {
+        let id_field = T::collection_id_field();
+        let values = self
+            .db
+            .distinct::<T, serde_json::Value>(id_field, filter)
+            .await?;
+        let ids = values
+            .iter()
+            .map(|id| match id {
+                serde_json::Value::String(id) => id.clone(),
+                other => other.to_string(),
+            })
+            .collect();
+        Ok((
+            filter.clone().and(Filter::In(id_field.to_string(), values)),
+            ids,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryCache, MemoryDB};
+
+    #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    struct Demo {
+        id: String,
+    }
+
+    impl Persistable for Demo {
+        fn collection_name() -> &'static str {
+            "demo"
+        }
+        fn collection_id(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    impl Cacheable for Demo {
+        fn cache_path() -> &'static str {
+            "demo"
+        }
+        fn cache_id(&self) -> String {
+            self.id.clone()
+        }
+        fn cache_expiry() -> usize {
+            3600
+        }
+    }
+
+    /// Like [Demo], but caches misses.
+    #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    struct Rare {
+        id: String,
+    }
+
+    impl Persistable for Rare {
+        fn collection_name() -> &'static str {
+            "rare"
+        }
+        fn collection_id(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    impl Cacheable for Rare {
+        fn cache_path() -> &'static str {
+            "rare"
+        }
+        fn cache_id(&self) -> String {
+            self.id.clone()
+        }
+        fn cache_expiry() -> usize {
+            3600
+        }
+        fn cache_miss_expiry() -> Option<usize> {
+            Some(60)
+        }
+    }
+
+    /// Cached under [Demo]'s path, but shaped differently.
+    #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+    struct Stale {
+        name: String,
+    }
+
+    impl Cacheable for Stale {
+        fn cache_path() -> &'static str {
+            "demo"
+        }
+        fn cache_id(&self) -> String {
+            self.name.clone()
+        }
+        fn cache_expiry() -> usize {
+            3600
+        }
+    }
+
+    fn config(strict_deletes: bool) -> Arc<DataServicesConfig> {
+        Arc::new(DataServicesConfig {
+            db_database: String::new(),
+            db_app_name: String::new(),
+            db_uri: String::new(),
+            cache_uri: String::new(),
+            strict_deletes,
+            cache_load_lock: None,
+            cache_self_heal: false,
+        })
+    }
+
+    fn services(strict_deletes: bool) -> MemoryDataServices {
+        DataServices::with_stores(
+            config(strict_deletes),
+            MemoryCache::default(),
+            MemoryDB::new(),
+        )
+    }
+
+    /// A [MemoryDB] that counts its [PersistStore::fetch_by_id] calls, each of which yields once
+    /// so that concurrent fetches overlap.  The next `stale_reads` of them find nothing, as if
+    /// they had run just before the object was added.
+    #[derive(Clone, Default)]
+    struct CountingDB {
+        db: MemoryDB,
+        fetches: Arc<std::sync::atomic::AtomicUsize>,
+        stale_reads: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl CountingDB {
+        fn fetches(&self) -> usize {
+            self.fetches.swap(0, std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl PersistStore for CountingDB {
+        async fn add<T>(&self, value: T) -> DaoResult<T>
+        where
+            T: core::fmt::Debug
+                + Clone
+                + Send
+                + Sync
+                + Unpin
+                + DeserializeOwned
+                + Serialize
+                + Persistable,
+        {
+            self.db.add(value).await
+        }
+
+        async fn fetch<T, K>(
+            &self,
+            key: Option<&str>,
+            value: Option<K>,
+        ) -> DaoResult<Option<Vec<T>>>
+        where
+            T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+            K: Serialize + Send + Sync,
+        {
+            self.db.fetch(key, value).await
+        }
+
+        async fn fetch_with<T>(
+            &self,
+            filter: &Filter,
+            options: &FetchOptions,
+        ) -> DaoResult<Option<Vec<T>>>
+        where
+            T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+        {
+            self.db.fetch_with(filter, options).await
+        }
+
+        async fn count<T>(&self, filter: &Filter) -> DaoResult<u64>
+        where
+            T: Persistable,
+        {
+            self.db.count::<T>(filter).await
+        }
+
+        async fn exists<T>(&self, id: &str) -> DaoResult<bool>
+        where
+            T: Persistable,
+        {
+            self.db.exists::<T>(id).await
+        }
+
+        async fn distinct<T, V>(&self, field: &str, filter: &Filter) -> DaoResult<Vec<V>>
+        where
+            T: Persistable,
+            V: DeserializeOwned + Send,
+        {
+            self.db.distinct::<T, V>(field, filter).await
+        }
+
+        async fn fetch_by_id<T>(&self, id: &str) -> DaoResult<Option<T>>
+        where
+            T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+        {
+            self.fetches
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            let stale = self
+                .stale_reads
+                .fetch_update(
+                    std::sync::atomic::Ordering::SeqCst,
+                    std::sync::atomic::Ordering::SeqCst,
+                    |reads| reads.checked_sub(1),
+                )
+                .is_ok();
+            match stale {
+                true => Ok(None),
+                false => self.db.fetch_by_id(id).await,
+            }
+        }
+
+        async fn update_with<T>(&self, id: &str, update: Update) -> DaoResult<T>
+        where
+            T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+        {
+            self.db.update_with(id, update).await
+        }
+
+        async fn add_many<T>(&self, values: &[T]) -> DaoResult<BulkReport>
+        where
+            T: Serialize + Send + Sync + Persistable,
+        {
+            self.db.add_many(values).await
+        }
+
+        async fn update_many<T>(&self, filter: &Filter, update: Update) -> DaoResult<u64>
+        where
+            T: Persistable,
+        {
+            self.db.update_many::<T>(filter, update).await
+        }
+
+        async fn replace<T>(&self, value: &T) -> DaoResult<()>
+        where
+            T: Serialize + Send + Sync + Persistable,
+        {
+            self.db.replace(value).await
+        }
+
+        async fn upsert<T>(&self, value: &T) -> DaoResult<()>
+        where
+            T: Serialize + Send + Sync + Persistable,
+        {
+            self.db.upsert(value).await
+        }
+
+        async fn delete<T>(&self, id: &str) -> DaoResult<bool>
+        where
+            T: Persistable,
+        {
+            self.db.delete::<T>(id).await
+        }
+
+        async fn take<T>(&self, id: &str) -> DaoResult<Option<T>>
+        where
+            T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+        {
+            self.db.take(id).await
+        }
+
+        async fn delete_many<T>(&self, ids: &[&str]) -> DaoResult<BulkReport>
+        where
+            T: Persistable,
+        {
+            self.db.delete_many::<T>(ids).await
+        }
+
+        async fn delete_where<T>(&self, filter: &Filter) -> DaoResult<u64>
+        where
+            T: Persistable,
+        {
+            self.db.delete_where::<T>(filter).await
+        }
+    }
+
+    #[test]
+    fn test_delete_cached() {
+        tokio_test::block_on(async {
+            let services = services(false);
+            services.add_cached(Demo { id: "a".into() }).await.unwrap();
+            assert!(services.delete_cached::<Demo>("a").await.unwrap());
+            assert_eq!(services.cache.fetch::<Demo>("a").await.unwrap(), None);
+            assert!(!services.delete_cached::<Demo>("a").await.unwrap());
+            assert_eq!(services.take::<Demo>("a").await.unwrap(), None);
+        })
+    }
+
+    #[test]
+    fn test_strict_deletes() {
+        tokio_test::block_on(async {
+            let services = services(true);
+            services.add(Demo { id: "a".into() }).await.unwrap();
+            assert_eq!(
+                services.take::<Demo>("a").await.unwrap(),
+                Some(Demo { id: "a".into() })
+            );
+            assert!(matches!(
+                services.delete::<Demo>("a").await,
+                Err(DaoError::NotFound)
+            ));
+            assert!(matches!(
+                services.take_cached::<Demo>("a").await,
+                Err(DaoError::NotFound)
+            ));
+        })
+    }
+
+    #[test]
+    fn test_cached_bulk_writes() {
+        tokio_test::block_on(async {
+            let services = services(false);
+            for id in ["a", "b"] {
+                services.add_cached(Demo { id: id.into() }).await.unwrap();
+            }
+            let (filter, ids) = services
+                .pin_matches::<Demo>(&Filter::ne("id", "b"))
+                .await
+                .unwrap();
+            assert_eq!(ids, ["a"]);
+
+            // Added after the ids were read, so the pinned filter leaves it alone
+            services.add(Demo { id: "c".into() }).await.unwrap();
+            assert_eq!(services.delete_where::<Demo>(&filter).await.unwrap(), 1);
+            assert!(services.exists::<Demo>("c").await.unwrap());
+
+            assert_eq!(
+                services
+                    .delete_where_cached::<Demo>(&Filter::all())
+                    .await
+                    .unwrap(),
+                2
+            );
+            assert_eq!(services.cache.fetch::<Demo>("b").await.unwrap(), None);
+            assert_eq!(
+                services
+                    .update_many_cached::<Demo>(&Filter::all(), Update::new())
+                    .await
+                    .unwrap(),
+                0
+            );
+        })
+    }
+
+    #[test]
+    fn test_concurrent_cached_fetches() {
+        tokio_test::block_on(async {
+            let db = CountingDB::default();
+            let mut services =
+                DataServices::with_stores(config(false), MemoryCache::default(), db.clone());
+            services.add(Demo { id: "a".into() }).await.unwrap();
+
+            let fetches = (0..8).map(|_| services.fetch_by_id_cached::<Demo>("a"));
+            for result in futures::future::join_all(fetches).await {
+                assert_eq!(result.unwrap(), Some(Demo { id: "a".into() }));
+            }
+            assert_eq!(db.fetches(), 1);
+            assert!(services.cache.fetch::<Demo>("a").await.unwrap().is_some());
+
+            // Demo doesn't cache misses, so only sharing the result saves the other reads
+            let fetches = (0..8).map(|_| services.fetch_by_id_cached::<Demo>("z"));
+            for result in futures::future::join_all(fetches).await {
+                assert_eq!(result.unwrap(), None);
+            }
+            assert_eq!(db.fetches(), 1);
+
+            Arc::make_mut(&mut services.config).cache_load_lock = Some(Duration::from_secs(1));
+            services.add(Demo { id: "b".into() }).await.unwrap();
+            let fetches = ["b", "b", "c"].map(|id| services.fetch_by_id_cached::<Demo>(id));
+            let results = futures::future::join_all(fetches).await;
+            assert_eq!(results[1].as_ref().unwrap(), &Some(Demo { id: "b".into() }));
+            assert_eq!(results[2].as_ref().unwrap(), &None);
+            assert_eq!(db.fetches(), 2);
+        })
+    }
+
+    #[test]
+    fn test_cached_misses() {
+        tokio_test::block_on(async {
+            let services = services(false);
+            assert_eq!(
+                services.fetch_by_id_cached::<Rare>("a").await.unwrap(),
+                None
+            );
+            assert!(services.cache.is_missing::<Rare>("a").await.unwrap());
+
+            // Added behind the cache's back, so the miss is still cached
+            services.add(Rare { id: "a".into() }).await.unwrap();
+            assert_eq!(
+                services.fetch_by_id_cached::<Rare>("a").await.unwrap(),
+                None
+            );
+            services.delete::<Rare>("a").await.unwrap();
+
+            services.add_cached(Rare { id: "a".into() }).await.unwrap();
+            assert!(!services.cache.is_missing::<Rare>("a").await.unwrap());
+            assert_eq!(
+                services.fetch_by_id_cached::<Rare>("a").await.unwrap(),
+                Some(Rare { id: "a".into() })
+            );
+
+            // Demo doesn't cache misses
+            assert_eq!(
+                services.fetch_by_id_cached::<Demo>("a").await.unwrap(),
+                None
+            );
+            assert!(!services.cache.is_missing::<Demo>("a").await.unwrap());
+        })
+    }
+
+    #[test]
+    fn test_cached_writes_clear_misses() {
+        tokio_test::block_on(async {
+            let db = CountingDB::default();
+            let services =
+                DataServices::with_stores(config(false), MemoryCache::default(), db.clone());
+            let rare = |id: &str| Rare { id: id.into() };
+
+            // The first read misses an object added alongside it, so the miss is taken back
+            services.add(rare("a")).await.unwrap();
+            db.stale_reads.store(1, std::sync::atomic::Ordering::SeqCst);
+            assert_eq!(
+                services.fetch_by_id_cached::<Rare>("a").await.unwrap(),
+                Some(rare("a"))
+            );
+            assert!(!services.cache.is_missing::<Rare>("a").await.unwrap());
+
+            // Every other cached write that stores an object under an id clears its miss
+            services.cache.put_missing::<Rare>("b").await.unwrap();
+            services
+                .update_cached::<Rare, &str>("a", "id", "b")
+                .await
+                .unwrap();
+            assert!(!services.cache.is_missing::<Rare>("b").await.unwrap());
+            // and the object is no longer cached under the id it moved off
+            assert_eq!(
+                services.fetch_by_id_cached::<Rare>("a").await.unwrap(),
+                None
+            );
+
+            services.cache.put_missing::<Rare>("b").await.unwrap();
+            services.replace_cached(&rare("b")).await.unwrap();
+            assert!(!services.cache.is_missing::<Rare>("b").await.unwrap());
+
+            services.cache.put_missing::<Rare>("b").await.unwrap();
+            services.cache.put_missing::<Rare>("c").await.unwrap();
+            services
+                .update_many_cached::<Rare>(&Filter::all(), Update::new().set("id", "c"))
+                .await
+                .unwrap();
+            assert!(!services.cache.is_missing::<Rare>("b").await.unwrap());
+            assert!(!services.cache.is_missing::<Rare>("c").await.unwrap());
+            assert_eq!(
+                services.fetch_by_id_cached::<Rare>("c").await.unwrap(),
+                Some(rare("c"))
+            );
+        })
+    }
+
+    #[test]
+    fn test_cache_self_heal() {
+        tokio_test::block_on(async {
+            let mut services = services(false);
+            services.add(Demo { id: "a".into() }).await.unwrap();
+            services
+                .cache
+                .put(&Stale { name: "a".into() })
+                .await
+                .unwrap();
+            assert!(matches!(
+                services.fetch_by_id_cached::<Demo>("a").await,
+                Err(DaoError::Deserialization { .. })
+            ));
+
+            Arc::make_mut(&mut services.config).cache_self_heal = true;
+            assert_eq!(
+                services.fetch_by_id_cached::<Demo>("a").await.unwrap(),
+                Some(Demo { id: "a".into() })
+            );
+            assert_eq!(
+                services.cache.fetch::<Demo>("a").await.unwrap(),
+                Some(Demo { id: "a".into() })
+            );
+        })
     }
 }
@@ -1,6 +1,6 @@
-use std::env;
+use std::{env, time::Duration};
 
-use super::DaoResult;
+use super::{DaoError, DaoResult};
 
 #[derive(Debug, Clone)]
 pub struct DataServicesConfig {
@@ -8,32 +8,83 @@ pub struct DataServicesConfig {
     pub db_app_name: String,
     pub db_uri: String,
     pub cache_uri: String,
+    /// Make [DataServices](crate::DataServices) deletes fail with
+    /// [DaoError::NotFound] when there is nothing to delete
+    pub strict_deletes: bool,
+    /// Coordinate cache misses across processes with a lock in the cache, held for at most
+    /// this long, so that only one process loads a missing object from the db
+    pub cache_load_lock: Option<Duration>,
+    /// Treat cached objects that fail to decode, such as those cached before a struct changed,
+    /// as misses: delete them and reload from the db, instead of failing the fetch
+    pub cache_self_heal: bool,
+}
+
+/// Read a required environment variable.
+fn var(name: &str) -> DaoResult<String> {
+    env::var(name).map_err(|_| {
+        log::error!("{} was not set", name);
+        DaoError::ConfigError(format!("{} was not set", name))
+    })
+}
+
+/// Read an optional `true`/`false` environment variable, defaulting to `false`.
+fn flag(name: &str) -> DaoResult<bool> {
+    match env::var(name) {
+        Err(_) => Ok(false),
+        Ok(value) => match value.to_ascii_lowercase().as_str() {
+            "true" | "1" => Ok(true),
+            "false" | "0" | "" => Ok(false),
+            _ => {
+                log::error!("{} must be true or false, not {}", name, value);
+                Err(DaoError::ConfigError(format!(
+                    "{} must be true or false, not {}",
+                    name, value
+                )))
+            }
+        },
+    }
+}
+
+/// Read an optional number of milliseconds from the environment.
+fn millis(name: &str) -> DaoResult<Option<Duration>> {
+    match env::var(name) {
+        Err(_) => Ok(None),
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => match value.parse::<u64>() {
+            Ok(ms) if ms > 0 => Ok(Some(Duration::from_millis(ms))),
+            _ => {
+                log::error!(
+                    "{} must be a positive number of milliseconds, not {}",
+                    name,
+                    value
+                );
+                Err(DaoError::ConfigError(format!(
+                    "{} must be a positive number of milliseconds, not {}",
+                    name, value
+                )))
+            }
+        },
+    }
 }
 
 impl DataServicesConfig {
     pub fn new() -> DaoResult<Self> {
-        let db_database = env::var("SWANKY_DB_DATABASE").map_err(|e| {
-            log::error!("SWANKY_DB_DATABASE was not set");
-            e
-        })?;
-        let db_app_name = env::var("SWANKY_DB_APP_NAME").map_err(|e| {
-            log::error!("SWANKY_DB_APP_NAME was not set");
-            e
-        })?;
-        let db_uri = env::var("SWANKY_DB_URI").map_err(|e| {
-            log::error!("SWANKY_DB_URI was not set");
-            e
-        })?;
-        let cache_uri = env::var("SWANKY_CACHE_URI").map_err(|e| {
-            log::error!("SWANKY_CACHE_URI was not set");
-            e
-        })?;
+        let db_database = var("SWANKY_DB_DATABASE")?;
+        let db_app_name = var("SWANKY_DB_APP_NAME")?;
+        let db_uri = var("SWANKY_DB_URI")?;
+        let cache_uri = var("SWANKY_CACHE_URI")?;
+        let strict_deletes = flag("SWANKY_STRICT_DELETES")?;
+        let cache_load_lock = millis("SWANKY_CACHE_LOAD_LOCK_MS")?;
+        let cache_self_heal = flag("SWANKY_CACHE_SELF_HEAL")?;
 
         Ok(Self {
             db_database,
             db_app_name,
             db_uri,
             cache_uri,
+            strict_deletes,
+            cache_load_lock,
+            cache_self_heal,
         })
     }
 }
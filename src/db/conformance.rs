@@ -0,0 +1,582 @@
+/// Behaviour every [PersistStore] shares, checked against each backend that can run in process.
+///
+/// Each backend's test module runs the whole suite against a fresh store with
+/// `conformance_tests!(store expression)`.
+use futures::TryStreamExt;
+use serde_json::{json, Value};
+
+use crate::{DaoError, FetchOptions, Filter, PersistStore, Persistable, SortOrder, Update};
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub(crate) struct Inner {
+    pub label: String,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub(crate) struct Demo {
+    pub id: String,
+    pub count: usize,
+    pub flag: bool,
+    pub inner: Inner,
+}
+
+impl Persistable for Demo {
+    fn collection_name() -> &'static str {
+        "demo"
+    }
+    fn collection_id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+pub(crate) fn demo(id: &str, count: usize) -> Demo {
+    Demo {
+        id: id.to_string(),
+        count,
+        flag: count > 1,
+        inner: Inner {
+            label: format!("label {}", count),
+        },
+    }
+}
+
+fn ids(items: impl IntoIterator<Item = Demo>) -> Vec<String> {
+    items.into_iter().map(|d| d.id).collect()
+}
+
+/// A schemaless document, for fields that are missing, null or of mixed types.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub(crate) struct Loose {
+    pub id: String,
+    #[serde(flatten)]
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Persistable for Loose {
+    fn collection_name() -> &'static str {
+        "loose"
+    }
+    fn collection_id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+pub(crate) fn loose(id: &str, fields: serde_json::Value) -> Loose {
+    Loose {
+        id: id.to_string(),
+        fields: serde_json::from_value(fields).unwrap(),
+    }
+}
+
+/// Reads [Loose] documents, but insists on a numeric `n`.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+pub(crate) struct Strict {
+    pub id: String,
+    pub n: u64,
+}
+
+impl Persistable for Strict {
+    fn collection_name() -> &'static str {
+        "loose"
+    }
+    fn collection_id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+async fn add_loose(db: &impl PersistStore, documents: Vec<Loose>) {
+    for document in documents {
+        db.add(document).await.unwrap();
+    }
+}
+
+/// Generate a `#[test]` for every check in the suite, each run against a fresh `$store`.
+macro_rules! conformance_tests {
+    ($store:expr) => {
+        $crate::db::conformance::conformance_tests!(
+            $store;
+            test_add_and_fetch_by_id,
+            test_fetch_by_value,
+            test_fetch_where,
+            test_array_and_null_filters,
+            test_fetch_page,
+            test_fetch_page_mixed_types,
+            test_fetch_stream,
+            test_count_exists_distinct,
+            test_update_and_delete,
+            test_update_with,
+            test_id_collisions,
+            test_replace_and_upsert,
+            test_bulk_operations,
+        );
+    };
+    ($store:expr; $($name:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $name() {
+                tokio_test::block_on($crate::db::conformance::$name($store));
+            }
+        )*
+    };
+}
+pub(crate) use conformance_tests;
+
+pub(crate) async fn test_add_and_fetch_by_id(db: impl PersistStore) {
+    db.add(demo("a", 1)).await.unwrap();
+    assert_eq!(
+        db.fetch_by_id::<Demo>("a").await.unwrap(),
+        Some(demo("a", 1))
+    );
+    assert_eq!(db.fetch_by_id::<Demo>("b").await.unwrap(), None);
+
+    let err = db.add(demo("a", 2)).await.unwrap_err();
+    assert!(matches!(err, DaoError::IdExists(id) if id == "a"));
+}
+
+pub(crate) async fn test_fetch_by_value(db: impl PersistStore) {
+    for (id, count) in [("a", 1), ("b", 2), ("c", 2)] {
+        db.add(demo(id, count)).await.unwrap();
+    }
+    let found = db
+        .fetch::<Demo, usize>(Some("count"), Some(2))
+        .await
+        .unwrap();
+    assert_eq!(found, Some(vec![demo("b", 2), demo("c", 2)]));
+
+    let found = db
+        .fetch::<Demo, bool>(Some("flag"), Some(false))
+        .await
+        .unwrap();
+    assert_eq!(found, Some(vec![demo("a", 1)]));
+
+    let found = db
+        .fetch::<Demo, &str>(Some("inner.label"), Some("label 1"))
+        .await
+        .unwrap();
+    assert_eq!(found, Some(vec![demo("a", 1)]));
+
+    // A string never matches a number
+    let found = db
+        .fetch::<Demo, &str>(Some("count"), Some("2"))
+        .await
+        .unwrap();
+    assert_eq!(found, None);
+
+    let all = db.fetch::<Demo, ()>(None, None).await.unwrap().unwrap();
+    assert_eq!(all.len(), 3);
+}
+
+pub(crate) async fn test_fetch_where(db: impl PersistStore) {
+    for (id, count) in [("a", 1), ("b", 2), ("c", 3)] {
+        db.add(demo(id, count)).await.unwrap();
+    }
+    let fetch = |filter: Filter| {
+        let db = db.clone();
+        async move {
+            ids(db
+                .fetch_where::<Demo>(&filter)
+                .await
+                .unwrap()
+                .unwrap_or_default())
+        }
+    };
+
+    assert_eq!(fetch(Filter::gte("count", 2)).await, ["b", "c"]);
+    assert_eq!(fetch(Filter::lt("count", 2.5)).await, ["a", "b"]);
+    assert_eq!(fetch(Filter::ne("id", "b")).await, ["a", "c"]);
+    assert_eq!(fetch(Filter::ne("missing", "b")).await.len(), 3);
+    assert_eq!(
+        fetch(Filter::is_in("id", ["a", "c", "z"])).await,
+        ["a", "c"]
+    );
+    assert_eq!(fetch(Filter::exists("inner.label")).await.len(), 3);
+    assert!(fetch(Filter::exists("inner.missing")).await.is_empty());
+    assert_eq!(
+        fetch(Filter::regex("inner.label", "[13]$")).await,
+        ["a", "c"]
+    );
+    assert_eq!(
+        fetch(Filter::eq("flag", true).and(!Filter::eq("count", 3))).await,
+        ["b"]
+    );
+    assert_eq!(
+        fetch(Filter::eq("id", "a").or(Filter::eq("id", "c"))).await,
+        ["a", "c"]
+    );
+    assert_eq!(fetch(Filter::all()).await.len(), 3);
+    assert!(fetch(Filter::Or(vec![])).await.is_empty());
+
+    assert!(db
+        .fetch_where::<Demo>(&Filter::regex("id", "("))
+        .await
+        .is_err());
+}
+
+pub(crate) async fn test_array_and_null_filters(db: impl PersistStore) {
+    add_loose(
+        &db,
+        vec![
+            loose("a", json!({"tags": ["dog", "cat"], "n": 1})),
+            loose("b", json!({"tags": "dog", "n": null})),
+            loose("c", json!({"tags": [["dog"], 2]})),
+            loose("d", json!({})),
+        ],
+    )
+    .await;
+    let fetch = |filter: Filter| {
+        let db = db.clone();
+        async move {
+            db.fetch_where::<Loose>(&filter)
+                .await
+                .unwrap()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|d| d.id)
+                .collect::<Vec<String>>()
+        }
+    };
+
+    assert_eq!(fetch(Filter::eq("tags", "dog")).await, ["a", "b"]);
+    assert_eq!(fetch(Filter::eq("tags", json!(["dog"]))).await, ["c"]);
+    assert_eq!(
+        fetch(Filter::eq("tags", json!(["dog", "cat"]))).await,
+        ["a"]
+    );
+    assert_eq!(fetch(Filter::eq("tags", 2)).await, ["c"]);
+    assert_eq!(fetch(Filter::ne("tags", "cat")).await, ["b", "c", "d"]);
+    assert_eq!(fetch(Filter::is_in("tags", ["cat", "bird"])).await, ["a"]);
+
+    assert_eq!(fetch(Filter::eq("n", Value::Null)).await, ["b", "c", "d"]);
+    assert_eq!(fetch(Filter::ne("n", Value::Null)).await, ["a"]);
+    assert_eq!(
+        fetch(Filter::is_in("n", [json!(1), Value::Null]))
+            .await
+            .len(),
+        4
+    );
+}
+
+pub(crate) async fn test_fetch_page(db: impl PersistStore) {
+    for (id, count) in [("a", 2), ("b", 1), ("c", 2), ("d", 3), ("e", 1)] {
+        db.add(demo(id, count)).await.unwrap();
+    }
+    let options = FetchOptions::new()
+        .sort_by("count", SortOrder::Descending)
+        .limit(2);
+
+    let sorted = db.fetch_with::<Demo>(&Filter::all(), &options).await;
+    assert_eq!(ids(sorted.unwrap().unwrap()), ["d", "a"]);
+
+    let mut pages = Vec::new();
+    let mut token = None;
+    loop {
+        let page = db
+            .fetch_page::<Demo>(&Filter::all(), &options, token.as_deref())
+            .await
+            .unwrap();
+        pages.push(ids(page.items));
+        token = page.next;
+        if token.is_none() {
+            break;
+        }
+    }
+    assert_eq!(pages, [vec!["d", "a"], vec!["c", "b"], vec!["e"]]);
+
+    let page = db
+        .fetch_page::<Demo>(&Filter::gte("count", 2), &options.clone().skip(1), None)
+        .await
+        .unwrap();
+    assert_eq!(ids(page.items), ["a", "c"]);
+    assert!(page.next.is_none());
+}
+
+pub(crate) async fn test_fetch_page_mixed_types(db: impl PersistStore) {
+    add_loose(
+        &db,
+        vec![
+            loose("a", json!({})),
+            loose("b", json!({})),
+            loose("c", json!({"n": 1})),
+            loose("d", json!({"n": 2})),
+            loose("e", json!({"n": null})),
+            loose("f", json!({"n": "x"})),
+            loose("g", json!({"n": 1.5})),
+            loose("h", json!({"n": {"x": 1}})),
+            loose("i", json!({"n": [2]})),
+            loose("j", json!({"n": true})),
+            loose("k", json!({"n": false})),
+            loose("l", json!({"n": 1})),
+            loose("m", json!({"n": {"$date": {"$numberLong": "2"}}})),
+            loose("n", json!({"n": {"$date": {"$numberLong": "1"}}})),
+            loose("o", json!({"n": {"$oid": "65a000000000000000000001"}})),
+        ],
+    )
+    .await;
+    let expected = [
+        [
+            "a", "b", "e", "c", "l", "g", "d", "f", "h", "i", "o", "k", "j", "n", "m",
+        ],
+        [
+            "m", "n", "j", "k", "o", "i", "h", "f", "d", "g", "c", "l", "a", "b", "e",
+        ],
+    ];
+
+    for (order, expected) in [SortOrder::Ascending, SortOrder::Descending]
+        .into_iter()
+        .zip(expected)
+    {
+        let options = FetchOptions::new().sort_by("n", order);
+        let sorted = db
+            .fetch_with::<Loose>(&Filter::all(), &options)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            sorted.into_iter().map(|d| d.id).collect::<Vec<_>>(),
+            expected
+        );
+
+        let mut paged = Vec::new();
+        let mut token = None;
+        loop {
+            let page = db
+                .fetch_page::<Loose>(&Filter::all(), &options.clone().limit(1), token.as_deref())
+                .await
+                .unwrap();
+            paged.extend(page.items.into_iter().map(|d| d.id));
+            token = page.next;
+            if token.is_none() {
+                break;
+            }
+        }
+        assert_eq!(paged, expected);
+    }
+
+    let options = FetchOptions::new().limit(0);
+    assert_eq!(
+        db.fetch_with::<Loose>(&Filter::all(), &options)
+            .await
+            .unwrap(),
+        None
+    );
+    let page = db
+        .fetch_page::<Loose>(&Filter::all(), &options, None)
+        .await
+        .unwrap();
+    assert!(page.items.is_empty() && page.next.is_none());
+}
+
+pub(crate) async fn test_fetch_stream(db: impl PersistStore) {
+    for (id, count) in [("a", 1), ("b", 2), ("c", 3)] {
+        db.add(demo(id, count)).await.unwrap();
+    }
+    let options = FetchOptions::new().sort_by("count", SortOrder::Descending);
+    let stream = db
+        .fetch_stream::<Demo>(&Filter::lt("count", 3), &options)
+        .await
+        .unwrap();
+    let found = stream.try_collect::<Vec<Demo>>().await.unwrap();
+    assert_eq!(found, vec![demo("b", 2), demo("a", 1)]);
+}
+
+pub(crate) async fn test_count_exists_distinct(db: impl PersistStore) {
+    for (id, count) in [("a", 1), ("b", 2), ("c", 2)] {
+        db.add(demo(id, count)).await.unwrap();
+    }
+    assert_eq!(db.count::<Demo>(&Filter::all()).await.unwrap(), 3);
+    assert_eq!(db.count::<Demo>(&Filter::eq("count", 2)).await.unwrap(), 2);
+    assert!(db.exists::<Demo>("a").await.unwrap());
+    assert!(!db.exists::<Demo>("z").await.unwrap());
+
+    let mut counts = db
+        .distinct::<Demo, i64>("count", &Filter::all())
+        .await
+        .unwrap();
+    counts.sort();
+    assert_eq!(counts, [1, 2]);
+    let ids = db
+        .distinct::<Demo, String>("id", &Filter::gt("count", 1))
+        .await
+        .unwrap();
+    assert_eq!(ids.len(), 2);
+    let mut flags = db
+        .distinct::<Demo, bool>("flag", &Filter::all())
+        .await
+        .unwrap();
+    flags.sort();
+    assert_eq!(flags, [false, true]);
+    assert!(db
+        .distinct::<Demo, i64>("missing", &Filter::all())
+        .await
+        .unwrap()
+        .is_empty());
+
+    add_loose(
+        &db,
+        vec![
+            loose("a", json!({"v": 2})),
+            loose("b", json!({"v": 2.0})),
+            loose("c", json!({"v": [2, "x", {"k": 1}]})),
+            loose("d", json!({"v": "x"})),
+            loose("e", json!({"v": {"k": 1}})),
+        ],
+    )
+    .await;
+    let values = db
+        .distinct::<Loose, Value>("v", &Filter::all())
+        .await
+        .unwrap();
+    assert_eq!(values, [json!(2), json!("x"), json!({"k": 1})]);
+}
+
+pub(crate) async fn test_update_and_delete(db: impl PersistStore) {
+    db.add(demo("a", 1)).await.unwrap();
+
+    let updated = db.update::<Demo, usize>("a", "count", 5).await.unwrap();
+    assert_eq!(updated.count, 5);
+    let updated = db
+        .update::<Demo, &str>("a", "inner.label", "relabelled")
+        .await
+        .unwrap();
+    assert_eq!(updated.inner.label, "relabelled");
+    assert_eq!(db.fetch_by_id::<Demo>("a").await.unwrap(), Some(updated));
+    assert!(matches!(
+        db.update::<Demo, usize>("b", "count", 5).await,
+        Err(DaoError::NotFound)
+    ));
+
+    db.update::<Demo, &str>("a", "id", "z").await.unwrap();
+    assert!(db.fetch_by_id::<Demo>("z").await.unwrap().is_some());
+    assert_eq!(db.fetch_by_id::<Demo>("a").await.unwrap(), None);
+
+    assert!(db.delete::<Demo>("z").await.unwrap());
+    assert_eq!(db.fetch_by_id::<Demo>("z").await.unwrap(), None);
+    assert!(!db.delete::<Demo>("z").await.unwrap());
+
+    db.add(demo("b", 2)).await.unwrap();
+    assert_eq!(db.take::<Demo>("b").await.unwrap(), Some(demo("b", 2)));
+    assert_eq!(db.take::<Demo>("b").await.unwrap(), None);
+
+    // Taking an object that can't be decoded leaves it in place
+    add_loose(&db, vec![loose("c", json!({"n": "x"}))]).await;
+    assert!(matches!(
+        db.take::<Strict>("c").await,
+        Err(DaoError::Deserialization { .. })
+    ));
+    assert!(db.fetch_by_id::<Loose>("c").await.unwrap().is_some());
+}
+
+/// Moving an object onto another's id is refused, leaving both as they were.
+pub(crate) async fn test_id_collisions(db: impl PersistStore) {
+    db.add(demo("a", 1)).await.unwrap();
+    db.add(demo("b", 2)).await.unwrap();
+    assert!(matches!(
+        db.update::<Demo, &str>("a", "id", "b").await,
+        Err(DaoError::IdExists(id)) if id == "b"
+    ));
+    assert!(matches!(
+        db.update_many::<Demo>(&Filter::all(), Update::new().set("id", "z"))
+            .await,
+        Err(DaoError::IdExists(id)) if id == "z"
+    ));
+    // An update that leaves an object unreadable changes nothing either
+    assert!(matches!(
+        db.update_with::<Demo>("a", Update::new().set("id", "c").set("count", "x"))
+            .await,
+        Err(DaoError::Deserialization { .. })
+    ));
+    assert_eq!(
+        db.fetch_by_id::<Demo>("a").await.unwrap(),
+        Some(demo("a", 1))
+    );
+    assert_eq!(
+        db.fetch_by_id::<Demo>("b").await.unwrap(),
+        Some(demo("b", 2))
+    );
+    assert_eq!(db.count::<Demo>(&Filter::all()).await.unwrap(), 2);
+}
+
+pub(crate) async fn test_update_with(db: impl PersistStore) {
+    db.add(demo("a", 1)).await.unwrap();
+
+    let update = Update::new()
+        .inc("count", 2)
+        .set("flag", true)
+        .set("inner.label", "relabelled");
+    let updated = db.update_with::<Demo>("a", update).await.unwrap();
+    assert_eq!((updated.count, updated.flag), (3, true));
+    assert_eq!(db.fetch_by_id::<Demo>("a").await.unwrap(), Some(updated));
+
+    // A failed operation leaves the object untouched
+    let update = Update::new().set("count", 10).push("flag", 1);
+    assert!(db.update_with::<Demo>("a", update).await.is_err());
+    assert_eq!(db.fetch_by_id::<Demo>("a").await.unwrap().unwrap().count, 3);
+
+    assert!(matches!(
+        db.update_with::<Demo>("b", Update::new().inc("count", 1))
+            .await,
+        Err(DaoError::NotFound)
+    ));
+}
+
+pub(crate) async fn test_replace_and_upsert(db: impl PersistStore) {
+    assert!(matches!(
+        db.replace(&demo("a", 1)).await,
+        Err(DaoError::NotFound)
+    ));
+
+    db.upsert(&demo("a", 1)).await.unwrap();
+    assert_eq!(
+        db.fetch_by_id::<Demo>("a").await.unwrap(),
+        Some(demo("a", 1))
+    );
+    db.upsert(&demo("a", 2)).await.unwrap();
+    assert_eq!(
+        db.fetch_by_id::<Demo>("a").await.unwrap(),
+        Some(demo("a", 2))
+    );
+
+    db.replace(&demo("a", 3)).await.unwrap();
+    assert_eq!(
+        db.fetch_by_id::<Demo>("a").await.unwrap(),
+        Some(demo("a", 3))
+    );
+    assert_eq!(db.count::<Demo>(&Filter::all()).await.unwrap(), 1);
+}
+
+pub(crate) async fn test_bulk_operations(db: impl PersistStore) {
+    db.add(demo("b", 1)).await.unwrap();
+
+    let report = db
+        .add_many(&[demo("a", 1), demo("b", 2), demo("c", 3), demo("a", 4)])
+        .await
+        .unwrap();
+    assert!(!report.is_complete());
+    assert_eq!(report.succeeded().collect::<Vec<_>>(), ["a", "c"]);
+    let failed = report.failed().map(|(id, _)| id).collect::<Vec<_>>();
+    assert_eq!(failed, ["b", "a"]);
+    assert!(report
+        .failed()
+        .all(|(_, err)| matches!(err, DaoError::IdExists(_))));
+    assert_eq!(
+        db.fetch_by_id::<Demo>("a").await.unwrap(),
+        Some(demo("a", 1))
+    );
+
+    let updated = db
+        .update_many::<Demo>(&Filter::lt("count", 3), Update::new().inc("count", 10))
+        .await
+        .unwrap();
+    assert_eq!(updated, 2);
+    assert_eq!(db.count::<Demo>(&Filter::gt("count", 10)).await.unwrap(), 2);
+
+    let report = db.delete_many::<Demo>(&["a", "z"]).await.unwrap();
+    assert_eq!(report.succeeded().collect::<Vec<_>>(), ["a"]);
+    assert!(matches!(
+        report.failed().next(),
+        Some(("z", DaoError::NotFound))
+    ));
+
+    assert_eq!(db.delete_where::<Demo>(&Filter::all()).await.unwrap(), 2);
+    assert_eq!(db.count::<Demo>(&Filter::all()).await.unwrap(), 0);
+}
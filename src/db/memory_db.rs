@@ -0,0 +1,514 @@
+/// In process persistence store.
+/// Objects are held as serde serialized JSON documents, grouped by [Persistable::collection_name]
+/// and keyed by [Persistable::collection_id].  Nothing is ever written to disk, which makes this
+/// store a good fit for tests and embedded use.
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    db::log_delete, fetch_options::sort_documents, update::apply_update, BulkReport, DaoError,
+    DaoResult, FetchOptions, Filter, PersistStore, Persistable, Update,
+};
+
+type Collections = HashMap<String, BTreeMap<String, Value>>;
+
+/// Clones share the same underlying collections.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryDB {
+    collections: Arc<RwLock<Collections>>,
+}
+
+impl MemoryDB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> DaoResult<RwLockReadGuard<'_, Collections>> {
+        self.collections
+            .read()
+            .map_err(|_| DaoError::ServiceError("MemoryDB: lock poisoned".to_string()))
+    }
+
+    fn write(&self) -> DaoResult<RwLockWriteGuard<'_, Collections>> {
+        self.collections
+            .write()
+            .map_err(|_| DaoError::ServiceError("MemoryDB: lock poisoned".to_string()))
+    }
+}
+
+/// Look up a possibly dotted (`"a.b.c"`) key in a document.
+pub(crate) fn lookup<'a>(document: &'a Value, key: &str) -> Option<&'a Value> {
+    key.split('.')
+        .try_fold(document, |value, part| value.as_object()?.get(part))
+}
+
+/// The id stored in a document's id field, as [Persistable::collection_id] would report it.
+pub(crate) fn document_id(document: &Value, id_field: &str) -> Option<String> {
+    match lookup(document, id_field)? {
+        Value::String(id) => Some(id.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+/// Flatten arrays into their elements and drop duplicates, keeping the first of each value.
+pub(crate) fn distinct_values(values: impl IntoIterator<Item = Value>) -> Vec<Value> {
+    let mut seen = HashSet::new();
+    let mut distinct = Vec::<Value>::new();
+    for value in values {
+        let elements = match value {
+            Value::Array(elements) => elements,
+            value => vec![value],
+        };
+        for element in elements {
+            if seen.insert(canonical(&element)) {
+                distinct.push(element);
+            }
+        }
+    }
+    distinct
+}
+
+/// Canonical JSON for a value, equal for values that [equal](crate::filter::equal) considers
+/// the same, so `2` and `2.0` share one.
+fn canonical(value: &Value) -> String {
+    match value {
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) => i.to_string(),
+            (None, Some(f)) if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 => {
+                (f as i64).to_string()
+            }
+            _ => value.to_string(),
+        },
+        value => value.to_string(),
+    }
+}
+
+#[async_trait]
+impl PersistStore for MemoryDB {
+    async fn add<T>(&self, value: T) -> DaoResult<T>
+    where
+        T: core::fmt::Debug
+            + Clone
+            + Send
+            + Sync
+            + Unpin
+            + DeserializeOwned
+            + Serialize
+            + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let id = value.collection_id();
+        let document = serde_json::to_value(&value)
+            .map_err(|e| DaoError::serialization::<T>(collection_name, e))?;
+
+        let mut collections = self.write()?;
+        let collection = collections.entry(collection_name.to_string()).or_default();
+        if collection.contains_key(&id) {
+            return Err(DaoError::IdExists(id));
+        }
+        collection.insert(id.clone(), document);
+        log::trace!("Added {}: {}", collection_name, id);
+        Ok(value)
+    }
+
+    async fn fetch<T, K>(&self, key: Option<&str>, value: Option<K>) -> DaoResult<Option<Vec<T>>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+        K: Serialize + Send + Sync,
+    {
+        let filter = match (key, value) {
+            (Some(k), Some(v)) => Filter::Eq(
+                k.to_string(),
+                serde_json::to_value(&v)
+                    .map_err(|e| DaoError::serialization::<K>(T::collection_name(), e))?,
+            ),
+            _ => Filter::all(),
+        };
+        self.fetch_where::<T>(&filter).await
+    }
+
+    async fn fetch_with<T>(
+        &self,
+        filter: &Filter,
+        options: &FetchOptions,
+    ) -> DaoResult<Option<Vec<T>>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        let matcher = filter.matcher()?;
+        let collection_name = T::collection_name();
+        let collections = self.read()?;
+        let Some(collection) = collections.get(collection_name) else {
+            return Ok(None);
+        };
+        let mut documents = collection
+            .values()
+            .filter(|document| matcher.matches(document))
+            .collect::<Vec<&Value>>();
+        sort_documents(&mut documents, &options.sort);
+        let result = documents
+            .into_iter()
+            .skip(options.skip.unwrap_or(0) as usize)
+            .take(options.limit.map_or(usize::MAX, |limit| limit as usize))
+            .map(|document| {
+                T::deserialize(document)
+                    .map_err(|e| DaoError::deserialization::<T>(collection_name, e))
+            })
+            .collect::<DaoResult<Vec<T>>>()?;
+
+        if result.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(result))
+    }
+
+    async fn count<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        let matcher = filter.matcher()?;
+        let collections = self.read()?;
+        let count = collections
+            .get(T::collection_name())
+            .map_or(0, |collection| {
+                collection
+                    .values()
+                    .filter(|document| matcher.matches(document))
+                    .count()
+            });
+        Ok(count as u64)
+    }
+
+    async fn exists<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Persistable,
+    {
+        let collections = self.read()?;
+        Ok(collections
+            .get(T::collection_name())
+            .is_some_and(|collection| collection.contains_key(id)))
+    }
+
+    async fn distinct<T, V>(&self, field: &str, filter: &Filter) -> DaoResult<Vec<V>>
+    where
+        T: Persistable,
+        V: DeserializeOwned + Send,
+    {
+        let matcher = filter.matcher()?;
+        let collection_name = T::collection_name();
+        let values = {
+            let collections = self.read()?;
+            let Some(collection) = collections.get(collection_name) else {
+                return Ok(Vec::new());
+            };
+            distinct_values(
+                collection
+                    .values()
+                    .filter(|document| matcher.matches(document))
+                    .filter_map(|document| lookup(document, field).cloned()),
+            )
+        };
+        values
+            .into_iter()
+            .map(|value| {
+                serde_json::from_value::<V>(value)
+                    .map_err(|e| DaoError::deserialization::<V>(collection_name, e))
+            })
+            .collect()
+    }
+
+    async fn fetch_by_id<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let collections = self.read()?;
+        match collections
+            .get(collection_name)
+            .and_then(|collection| collection.get(id))
+        {
+            Some(document) => {
+                log::trace!(
+                    "Fetched {} - {}:{}",
+                    collection_name,
+                    T::collection_id_field(),
+                    id
+                );
+                let result = T::deserialize(document)
+                    .map_err(|e| DaoError::deserialization::<T>(collection_name, e))?;
+                Ok(Some(result))
+            }
+            None => {
+                log::trace!(
+                    "Fetch not found: {} - {}:{}",
+                    collection_name,
+                    T::collection_id_field(),
+                    id
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    async fn update_with<T>(&self, id: &str, update: Update) -> DaoResult<T>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let operations = update.into_operations()?;
+
+        let mut collections = self.write()?;
+        let Some(collection) = collections.get_mut(collection_name) else {
+            return Err(DaoError::NotFound);
+        };
+        let Some(mut document) = collection.get(id).cloned() else {
+            return Err(DaoError::NotFound);
+        };
+
+        let updated = apply_update(&mut document, &operations).and_then(|_| {
+            T::deserialize(&document)
+                .map_err(|e| DaoError::deserialization::<T>(collection_name, e))
+        });
+        let updated = updated.and_then(|object| {
+            // The id field may have been the one updated, but not to another object's id.
+            let new_id = object.collection_id();
+            match new_id != id && collection.contains_key(&new_id) {
+                true => Err(DaoError::IdExists(new_id)),
+                false => Ok(object),
+            }
+        });
+        match updated {
+            Ok(object) => {
+                collection.remove(id);
+                collection.insert(object.collection_id(), document);
+                log::trace!("Updated {}: {}", collection_name, id);
+                Ok(object)
+            }
+            Err(err) => {
+                log::error!("Error updating {}: {:?}", collection_name, &err);
+                Err(err)
+            }
+        }
+    }
+
+    async fn add_many<T>(&self, values: &[T]) -> DaoResult<BulkReport>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let mut collections = self.write()?;
+        let collection = collections.entry(collection_name.to_string()).or_default();
+        let report = values
+            .iter()
+            .map(|value| {
+                let id = value.collection_id();
+                let result = serde_json::to_value(value)
+                    .map_err(|e| DaoError::serialization::<T>(collection_name, e))
+                    .and_then(|document| match collection.contains_key(&id) {
+                        true => Err(DaoError::IdExists(id.clone())),
+                        false => {
+                            collection.insert(id.clone(), document);
+                            Ok(())
+                        }
+                    });
+                (id, result)
+            })
+            .collect::<BulkReport>();
+        log::trace!(
+            "Added {} of {} to {}",
+            report.succeeded().count(),
+            values.len(),
+            collection_name
+        );
+        Ok(report)
+    }
+
+    async fn update_many<T>(&self, filter: &Filter, update: Update) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        let matcher = filter.matcher()?;
+        let collection_name = T::collection_name();
+        let operations = update.into_operations()?;
+
+        let mut collections = self.write()?;
+        let Some(collection) = collections.get_mut(collection_name) else {
+            return Ok(0);
+        };
+        // Apply everything to copies first, so that a failure leaves the collection untouched
+        let mut updated = Vec::new();
+        for (id, document) in collection.iter() {
+            if matcher.matches(document) {
+                let mut document = document.clone();
+                apply_update(&mut document, &operations)?;
+                // The id field may have been one of those updated.
+                let new_id = document_id(&document, T::collection_id_field()).unwrap_or(id.clone());
+                updated.push((id.clone(), new_id, document));
+            }
+        }
+
+        // No object may end up with the id of another, whether or not that one was updated too
+        let mut ids = collection.keys().cloned().collect::<HashSet<String>>();
+        for (id, _, _) in &updated {
+            ids.remove(id);
+        }
+        for (_, new_id, _) in &updated {
+            if !ids.insert(new_id.clone()) {
+                return Err(DaoError::IdExists(new_id.clone()));
+            }
+        }
+
+        let count = updated.len() as u64;
+        for (id, _, _) in &updated {
+            collection.remove(id);
+        }
+        for (_, new_id, document) in updated {
+            collection.insert(new_id, document);
+        }
+        log::trace!("Updated {} in {}", count, collection_name);
+        Ok(count)
+    }
+
+    async fn replace<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let id = value.collection_id();
+        let document = serde_json::to_value(value)
+            .map_err(|e| DaoError::serialization::<T>(collection_name, e))?;
+
+        let mut collections = self.write()?;
+        match collections
+            .get_mut(collection_name)
+            .and_then(|collection| collection.get_mut(&id))
+        {
+            Some(existing) => {
+                *existing = document;
+                log::trace!("Replaced {}: {}", collection_name, id);
+                Ok(())
+            }
+            None => Err(DaoError::NotFound),
+        }
+    }
+
+    async fn upsert<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let id = value.collection_id();
+        let document = serde_json::to_value(value)
+            .map_err(|e| DaoError::serialization::<T>(collection_name, e))?;
+
+        let mut collections = self.write()?;
+        collections
+            .entry(collection_name.to_string())
+            .or_default()
+            .insert(id.clone(), document);
+        log::trace!("Upserted {}: {}", collection_name, id);
+        Ok(())
+    }
+
+    async fn delete<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Persistable,
+    {
+        let collection_name = T::collection_name();
+        let removed = self
+            .write()?
+            .get_mut(collection_name)
+            .and_then(|collection| collection.remove(id));
+        log_delete(
+            collection_name,
+            T::collection_id_field(),
+            id,
+            removed.is_some(),
+        );
+        Ok(removed.is_some())
+    }
+
+    async fn take<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let mut collections = self.write()?;
+        let Some(collection) = collections.get_mut(collection_name) else {
+            log_delete(collection_name, T::collection_id_field(), id, false);
+            return Ok(None);
+        };
+        // Check the object can be returned before removing it
+        let result = match collection.get(id) {
+            Some(document) => T::deserialize(document)
+                .map(Some)
+                .map_err(|e| DaoError::deserialization::<T>(collection_name, e))?,
+            None => None,
+        };
+        collection.remove(id);
+        log_delete(
+            collection_name,
+            T::collection_id_field(),
+            id,
+            result.is_some(),
+        );
+        Ok(result)
+    }
+
+    async fn delete_many<T>(&self, ids: &[&str]) -> DaoResult<BulkReport>
+    where
+        T: Persistable,
+    {
+        let collection_name = T::collection_name();
+        let mut collections = self.write()?;
+        let mut collection = collections.get_mut(collection_name);
+        let report = ids
+            .iter()
+            .map(|id| {
+                let removed = collection
+                    .as_mut()
+                    .and_then(|collection| collection.remove(*id));
+                let result = removed.map(|_| ()).ok_or(DaoError::NotFound);
+                (id.to_string(), result)
+            })
+            .collect::<BulkReport>();
+        log::trace!(
+            "Deleted {} of {} from {}",
+            report.succeeded().count(),
+            ids.len(),
+            collection_name
+        );
+        Ok(report)
+    }
+
+    async fn delete_where<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        let matcher = filter.matcher()?;
+        let collection_name = T::collection_name();
+        let mut collections = self.write()?;
+        let Some(collection) = collections.get_mut(collection_name) else {
+            return Ok(0);
+        };
+        let before = collection.len();
+        collection.retain(|_, document| !matcher.matches(document));
+        let count = (before - collection.len()) as u64;
+        log::trace!("Deleted {} from {}", count, collection_name);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::conformance::conformance_tests;
+
+    conformance_tests!(MemoryDB::new());
+}
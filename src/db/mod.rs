@@ -1,7 +1,21 @@
 /// Data persistence abstraction layer
-/// The crate currently only supports Mongodb.  But extending to support other
-/// services is as simple as adding another target and then updating the feature flags in
-/// [Cargo.toml](./Cargo.toml)
+/// MongoDB is the primary target, and is available with the `mongodb` feature.  Extending to
+/// support other services is as simple as implementing [PersistStore] for another target and then
+/// updating the feature flags in [Cargo.toml](./Cargo.toml)
+/// [MemoryDB] is always available, and keeps everything in process.
+/// `SqliteDB` is available with the `sqlite` feature.
+pub use memory_db::*;
+#[cfg(feature = "mongodb")]
 pub use mongo_db::*;
+pub use persist_store::*;
+#[cfg(feature = "sqlite")]
+pub use sqlite_db::*;
 
+#[cfg(test)]
+pub(crate) mod conformance;
+pub mod memory_db;
+#[cfg(feature = "mongodb")]
 pub mod mongo_db;
+pub mod persist_store;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_db;
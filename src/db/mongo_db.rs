@@ -1,20 +1,40 @@
-use futures::stream::TryStreamExt;
-use std::sync::Arc;
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use mongodb::{
-    bson::{doc, Bson},
-    options::ClientOptions,
-    Client, Database,
+    bson::{self, doc, Bson, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{
+        ClientOptions, CountOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions,
+        InsertManyOptions, ReplaceOptions, ReturnDocument,
+    },
+    Client, Database, IndexModel,
 };
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::{DaoError, DaoResult, DataServicesConfig, Persistable};
+use crate::{
+    db::log_delete, BulkReport, DaoError, DaoResult, DaoStream, DataServicesConfig, FetchOptions,
+    Filter, PersistStore, Persistable, SortOrder, Update, UpdateOp, ValueType,
+};
+
+/// MongoDB's duplicate key error code
+const DUPLICATE_KEY: i32 = 11000;
+/// MongoDB's error codes for creating an index that clashes with an existing one
+const INDEX_CONFLICTS: [i32; 2] = [85, 86];
+/// How many of [PersistStore::delete_many]'s deletes are in flight at once
+const DELETE_CONCURRENCY: usize = 16;
 
 #[derive(Clone, Debug)]
 pub struct DB {
     pub config: Arc<DataServicesConfig>,
     pub client: Client,
     pub database: Database,
+    /// The name of each collection's id index, so we only create it once per collection
+    indexed: Arc<Mutex<HashMap<String, String>>>,
 }
 
 impl DB {
@@ -33,57 +53,101 @@ impl DB {
             config,
             client,
             database,
+            indexed: Default::default(),
         })
     }
 
-    pub async fn add<T>(&self, value: T) -> DaoResult<T>
+    /// Make sure the collection for `T` has a unique index on [Persistable::collection_id_field].
+    /// [PersistStore::add] calls this before its first insert into each collection, so calling it
+    /// directly is only needed to surface problems early, e.g. at startup.
+    ///
+    /// Fails if the collection already holds duplicate ids.  MongoDB always indexes `_id` uniquely,
+    /// so nothing is created for it, and an existing unique index on the id field is used as is,
+    /// whatever its name.
+    pub async fn ensure_id_index<T>(&self) -> DaoResult<()>
     where
-        T: core::fmt::Debug
-            + Clone
-            + Send
-            + Sync
-            + Unpin
-            + DeserializeOwned
-            + Serialize
-            + Persistable,
+        T: Persistable,
+    {
+        self.id_index::<T>().await.map(|_| ())
+    }
+
+    /// The name of the unique index on [Persistable::collection_id_field] for `T`, creating the
+    /// index if needed.
+    async fn id_index<T>(&self) -> DaoResult<String>
+    where
+        T: Persistable,
     {
         let collection_name = T::collection_name();
+        let id_field = T::collection_id_field();
+        let poisoned = || DaoError::ServiceError("MongoDB: index lock poisoned".to_string());
+        if let Some(name) = self
+            .indexed
+            .lock()
+            .map_err(|_| poisoned())?
+            .get(collection_name)
+        {
+            return Ok(name.clone());
+        }
 
-        let collection = self.database.collection::<T>(collection_name);
-        match self.fetch_by_id::<T>(&value.collection_id()).await? {
-            Some(_) => Err(DaoError::IdExists(value.collection_id()).into()),
-            None => match collection.insert_one(&value, None).await {
-                Ok(_) => {
-                    log::trace!("Added {}: {}", collection_name, value.collection_id());
-                    Ok(value)
+        let name = match id_field {
+            // MongoDB refuses the unique option on _id, whose index is unique anyway
+            "_id" => "_id_".to_string(),
+            _ => {
+                // Creating an index that already exists is a no-op, so racing callers are harmless
+                let collection = self.database.collection::<Document>(collection_name);
+                let index = IndexModel::builder()
+                    .keys(doc! {id_field: 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build();
+                match collection.create_index(index, None).await {
+                    Ok(result) => result.index_name,
+                    // The field may already be uniquely indexed under another name
+                    Err(err) if is_index_conflict(&err) => {
+                        let mut indexes = collection.list_indexes(None).await?;
+                        let mut existing = None;
+                        while let Some(index) = indexes.try_next().await? {
+                            let unique = index.options.as_ref().and_then(|o| o.unique);
+                            if index.keys.len() == 1
+                                && index.keys.contains_key(id_field)
+                                && unique == Some(true)
+                            {
+                                existing = index.options.and_then(|o| o.name);
+                                break;
+                            }
+                        }
+                        existing.ok_or_else(|| {
+                            log::error!("Error indexing {}: {:?}", collection_name, &err);
+                            DaoError::DatabaseError(err)
+                        })?
+                    }
+                    Err(err) => {
+                        log::error!("Error indexing {}: {:?}", collection_name, &err);
+                        return Err(DaoError::DatabaseError(err));
+                    }
                 }
-                Err(err) => {
-                    log::error!("Error saving {}: {:?}", collection_name, &err);
-                    Err(DaoError::DatabaseError(err).into())
-                }
-            },
-        }
+            }
+        };
+        self.indexed
+            .lock()
+            .map_err(|_| poisoned())?
+            .insert(collection_name.to_string(), name.clone());
+        Ok(name)
     }
 
-    pub async fn fetch<T, K>(
+    /// Run a find against the collection for `T`, collecting every match.
+    async fn find<T>(
         &self,
-        key: Option<&str>,
-        value: Option<K>,
+        filter: Document,
+        options: Option<FindOptions>,
     ) -> DaoResult<Option<Vec<T>>>
     where
         T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
-        K: Serialize,
     {
         let collection_name = T::collection_name();
-        let filter = match (key, value) {
-            (Some(k), Some(v)) => doc! {k: serde_json::to_string(&v)?},
-            _ => doc! {},
-        };
-
         let cursor_result = self
             .database
             .collection::<T>(collection_name)
-            .find(filter, None)
+            .find(filter, options)
             .await
             .map_err(DaoError::DatabaseError);
 
@@ -97,12 +161,301 @@ impl DB {
             }
             Err(e) => {
                 log::trace!("fetch returned en error: {:?}", e);
-                Err(e.into())
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Translate backend neutral [UpdateOp]s into a MongoDB update document.
+/// Values are converted from extended JSON, the same as in [filter_document].
+pub(crate) fn update_document(
+    collection_name: &str,
+    operations: &[UpdateOp],
+) -> DaoResult<Document> {
+    let bson = |value: &serde_json::Value| {
+        Bson::try_from(value.clone())
+            .map_err(|e| DaoError::serialization::<serde_json::Value>(collection_name, e))
+    };
+
+    let mut update = Document::new();
+    for op in operations {
+        let (operator, key, value) = match op {
+            UpdateOp::Set(key, value) => ("$set", key, bson(value)?),
+            UpdateOp::Unset(key) => ("$unset", key, Bson::String(String::new())),
+            UpdateOp::Inc(key, value) => ("$inc", key, bson(value)?),
+            UpdateOp::Push(key, value) => ("$push", key, bson(value)?),
+            UpdateOp::Pull(key, value) => ("$pull", key, bson(value)?),
+            UpdateOp::AddToSet(key, value) => ("$addToSet", key, bson(value)?),
+        };
+        match update.get_mut(operator) {
+            Some(Bson::Document(fields)) => {
+                fields.insert(key, value);
+            }
+            _ => {
+                update.insert(operator, doc! {key: value});
             }
         }
     }
+    Ok(update)
+}
+
+/// Whether creating an index failed because one with other options or another name exists.
+fn is_index_conflict(err: &mongodb::error::Error) -> bool {
+    matches!(err.kind.as_ref(), ErrorKind::Command(e) if INDEX_CONFLICTS.contains(&e.code))
+}
+
+/// Whether a write failed because it would have duplicated a key in the index named
+/// `id_index`.  Duplicates in any other unique index don't count.
+pub(crate) fn is_duplicate_key(err: &mongodb::error::Error, id_index: &str) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => {
+            is_duplicate_id(e.code, &e.message, id_index)
+        }
+        ErrorKind::BulkWrite(failure) => failure
+            .write_errors
+            .iter()
+            .flatten()
+            .any(|e| is_duplicate_id(e.code, &e.message, id_index)),
+        _ => false,
+    }
+}
+
+/// Whether a write error's code and message report a duplicate key in the index named `id_index`.
+/// The driver doesn't expose the index, so it is read from the message, which looks like
+/// `E11000 duplicate key error collection: db.demo index: id_1 dup key: { id: "a" }`.
+/// Older servers name the index `db.demo.$id_1`.
+fn is_duplicate_id(code: i32, message: &str, id_index: &str) -> bool {
+    code == DUPLICATE_KEY
+        && message
+            .split_once(" index: ")
+            .and_then(|(_, rest)| rest.split_once(" dup key"))
+            .is_some_and(|(index, _)| index.rsplit('$').next() == Some(id_index))
+}
+
+/// Translate backend neutral [FetchOptions] into MongoDB [FindOptions].
+pub(crate) fn find_options(options: &FetchOptions) -> FindOptions {
+    let sort = options
+        .sort
+        .iter()
+        .map(|(key, order)| {
+            let direction = match order {
+                SortOrder::Ascending => 1,
+                SortOrder::Descending => -1,
+            };
+            (key.clone(), Bson::Int32(direction))
+        })
+        .collect::<Document>();
+    FindOptions::builder()
+        .sort((!sort.is_empty()).then_some(sort))
+        .limit(
+            options
+                .limit
+                .map(|limit| i64::try_from(limit).unwrap_or(i64::MAX)),
+        )
+        .skip(options.skip)
+        .build()
+}
+
+/// Build a filter matching documents where `key` equals `value`.
+/// The value is converted with [bson::to_bson], so it is compared as its native BSON type
+/// (numbers, booleans, dates, ObjectIds, ...).  Dotted keys (`"a.b.c"`) reach into embedded
+/// documents.
+pub(crate) fn field_filter<K>(collection_name: &str, key: &str, value: &K) -> DaoResult<Document>
+where
+    K: Serialize + ?Sized,
+{
+    let value =
+        bson::to_bson(value).map_err(|e| DaoError::serialization::<K>(collection_name, e))?;
+    Ok(doc! {key: value})
+}
 
-    pub async fn fetch_by_id<T>(&self, id: &str) -> DaoResult<Option<T>>
+/// Translate a [Filter] into a MongoDB query document.
+/// Filter values in MongoDB extended JSON (`{"$oid": ...}`, `{"$date": ...}`) become their native
+/// BSON types.
+pub(crate) fn filter_document(collection_name: &str, filter: &Filter) -> DaoResult<Document> {
+    let bson = |value: &serde_json::Value| {
+        Bson::try_from(value.clone())
+            .map_err(|e| DaoError::serialization::<Filter>(collection_name, e))
+    };
+    let operator = |key: &str, op: &str, value: &serde_json::Value| -> DaoResult<Document> {
+        Ok(doc! {key: {op: bson(value)?}})
+    };
+    let all = |filters: &[Filter]| {
+        filters
+            .iter()
+            .map(|filter| filter_document(collection_name, filter))
+            .collect::<DaoResult<Vec<Document>>>()
+    };
+
+    match filter {
+        Filter::Eq(key, value) => operator(key, "$eq", value),
+        Filter::Ne(key, value) => operator(key, "$ne", value),
+        Filter::Gt(key, value) => operator(key, "$gt", value),
+        Filter::Gte(key, value) => operator(key, "$gte", value),
+        Filter::Lt(key, value) => operator(key, "$lt", value),
+        Filter::Lte(key, value) => operator(key, "$lte", value),
+        Filter::In(key, values) => {
+            let values = values.iter().map(bson).collect::<DaoResult<Vec<Bson>>>()?;
+            Ok(doc! {key: {"$in": values}})
+        }
+        Filter::Exists(key, exists) => Ok(doc! {key: {"$exists": exists}}),
+        Filter::Regex(key, pattern) => Ok(doc! {key: {"$regex": pattern}}),
+        Filter::Type(key, value_type) => {
+            let alias = match value_type {
+                ValueType::Null => "null",
+                ValueType::Number => "number",
+                ValueType::String => "string",
+                ValueType::Object => "object",
+                ValueType::Array => "array",
+                ValueType::ObjectId => "objectId",
+                ValueType::Bool => "bool",
+                ValueType::Date => "date",
+            };
+            Ok(doc! {key: {"$type": alias}})
+        }
+        Filter::And(filters) if filters.is_empty() => Ok(doc! {}),
+        Filter::And(filters) => Ok(doc! {"$and": all(filters)?}),
+        // $or rejects an empty list, so match nothing explicitly
+        Filter::Or(filters) if filters.is_empty() => Ok(doc! {"$expr": false}),
+        Filter::Or(filters) => Ok(doc! {"$or": all(filters)?}),
+        Filter::Not(filter) => Ok(doc! {"$nor": [filter_document(collection_name, filter)?]}),
+    }
+}
+
+#[async_trait]
+impl PersistStore for DB {
+    async fn add<T>(&self, value: T) -> DaoResult<T>
+    where
+        T: core::fmt::Debug
+            + Clone
+            + Send
+            + Sync
+            + Unpin
+            + DeserializeOwned
+            + Serialize
+            + Persistable,
+    {
+        let collection_name = T::collection_name();
+
+        let id_index = self.id_index::<T>().await?;
+
+        // The unique index rejects duplicates atomically, so no need to look first
+        let collection = self.database.collection::<T>(collection_name);
+        match collection.insert_one(&value, None).await {
+            Ok(_) => {
+                log::trace!("Added {}: {}", collection_name, value.collection_id());
+                Ok(value)
+            }
+            Err(err) if is_duplicate_key(&err, &id_index) => {
+                Err(DaoError::IdExists(value.collection_id()))
+            }
+            Err(err) => {
+                log::error!("Error saving {}: {:?}", collection_name, &err);
+                Err(DaoError::DatabaseError(err))
+            }
+        }
+    }
+
+    async fn fetch<T, K>(&self, key: Option<&str>, value: Option<K>) -> DaoResult<Option<Vec<T>>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+        K: Serialize + Send + Sync,
+    {
+        let filter = match (key, value) {
+            (Some(k), Some(v)) => field_filter(T::collection_name(), k, &v)?,
+            _ => doc! {},
+        };
+        self.find::<T>(filter, None).await
+    }
+
+    async fn fetch_with<T>(
+        &self,
+        filter: &Filter,
+        options: &FetchOptions,
+    ) -> DaoResult<Option<Vec<T>>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        // MongoDB reads a limit of 0 as no limit at all
+        if options.limit == Some(0) {
+            return Ok(None);
+        }
+        let filter = filter_document(T::collection_name(), filter)?;
+        self.find::<T>(filter, Some(find_options(options))).await
+    }
+
+    async fn fetch_stream<T>(
+        &self,
+        filter: &Filter,
+        options: &FetchOptions,
+    ) -> DaoResult<DaoStream<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable + 'static,
+    {
+        if options.limit == Some(0) {
+            return Ok(stream::empty().boxed());
+        }
+        let filter = filter_document(T::collection_name(), filter)?;
+        // The cursor only asks the server for its next batch once the current one has been
+        // consumed, so a slow reader holds back the fetch.
+        let cursor = self
+            .database
+            .collection::<T>(T::collection_name())
+            .find(filter, find_options(options))
+            .await?;
+        Ok(cursor.map_err(DaoError::DatabaseError).boxed())
+    }
+
+    async fn count<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        let filter = filter_document(T::collection_name(), filter)?;
+        let count = self
+            .database
+            .collection::<Document>(T::collection_name())
+            .count_documents(filter, None)
+            .await?;
+        Ok(count)
+    }
+
+    async fn exists<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Persistable,
+    {
+        let filter = doc! {T::collection_id_field(): id.to_string()};
+        let options = CountOptions::builder().limit(1).build();
+        let count = self
+            .database
+            .collection::<Document>(T::collection_name())
+            .count_documents(filter, options)
+            .await?;
+        Ok(count > 0)
+    }
+
+    async fn distinct<T, V>(&self, field: &str, filter: &Filter) -> DaoResult<Vec<V>>
+    where
+        T: Persistable,
+        V: DeserializeOwned + Send,
+    {
+        let collection_name = T::collection_name();
+        let filter = filter_document(collection_name, filter)?;
+        let values = self
+            .database
+            .collection::<Document>(collection_name)
+            .distinct(field, filter, None)
+            .await?;
+        values
+            .into_iter()
+            .map(|value| {
+                bson::from_bson::<V>(value)
+                    .map_err(|e| DaoError::deserialization::<V>(collection_name, e))
+            })
+            .collect()
+    }
+
+    async fn fetch_by_id<T>(&self, id: &str) -> DaoResult<Option<T>>
     where
         T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
     {
@@ -137,61 +490,545 @@ impl DB {
             },
             Err(e) => {
                 log::trace!("fetch_by_id returned en error: {:?}", e);
-                Err(e.into())
+                Err(e)
             }
         }
     }
 
-    /// Persisted objects are essentially  hierarchical key value stores.  Let's start with the
-    /// top level objects, since we will be micro adjusting them.  So, update needs:
-    /// - the object id
-    /// - the field name of the value being updated
-    /// - the new value for that field
-    pub async fn update<T, K>(&self, id: &str, key: &str, value: K) -> DaoResult<Option<T>>
+    async fn update_with<T>(&self, id: &str, update: Update) -> DaoResult<T>
     where
         T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
-        K: Clone + Serialize + Into<Bson>,
     {
         let collection_name = T::collection_name();
-        let collection = self.database.collection::<T>(collection_name);
+        // The id the object ends up with, for reporting a collision
+        let new_id = match update.set_value(T::collection_id_field()) {
+            Some(serde_json::Value::String(new_id)) => new_id.clone(),
+            Some(new_id) => new_id.to_string(),
+            None => id.to_string(),
+        };
+        let update = update_document(collection_name, &update.into_operations()?)?;
+        // MongoDB rejects an empty update, so just read the object back
+        if update.is_empty() {
+            return self.fetch_by_id::<T>(id).await?.ok_or(DaoError::NotFound);
+        }
+        let id_index = self.id_index::<T>().await?;
 
         let filter = doc! {T::collection_id_field(): &id.to_string()};
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        match self
+            .database
+            .collection::<Document>(collection_name)
+            .find_one_and_update(filter, update, options)
+            .await
+        {
+            Ok(Some(document)) => {
+                log::trace!("Updated {}: {}", collection_name, id);
+                // The update is already stored, so a failure here can't undo it
+                bson::from_document::<T>(document)
+                    .map_err(|e| DaoError::deserialization::<T>(collection_name, e))
+            }
+            Ok(None) => {
+                log::trace!("Update not found: {}: {}", collection_name, id);
+                Err(DaoError::NotFound)
+            }
+            Err(err) if is_duplicate_key(&err, &id_index) => Err(DaoError::IdExists(new_id)),
+            Err(err) => {
+                log::error!("Error updating {}: {:?}", collection_name, &err);
+                Err(DaoError::DatabaseError(err))
+            }
+        }
+    }
 
-        let set = doc! {"$set": doc! {key: &value.into()}};
+    async fn add_many<T>(&self, values: &[T]) -> DaoResult<BulkReport>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        // insert_many rejects an empty batch
+        if values.is_empty() {
+            return Ok(BulkReport::default());
+        }
+        let id_index = self.id_index::<T>().await?;
 
-        match collection.update_one(filter, set, None).await {
-            Ok(res) => {
-                log::trace!("Updated {}: {} - {:?}", collection_name, id, &res);
-                self.fetch_by_id::<T>(id).await
+        // Unordered, so that one failure doesn't stop the rest of the batch
+        let mut report = values
+            .iter()
+            .map(|value| (value.collection_id(), Ok(())))
+            .collect::<BulkReport>();
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let result = self
+            .database
+            .collection::<T>(collection_name)
+            .insert_many(values, options)
+            .await;
+        if let Err(err) = result {
+            let ErrorKind::BulkWrite(failure) = err.kind.as_ref() else {
+                log::error!("Error saving {}: {:?}", collection_name, &err);
+                return Err(DaoError::DatabaseError(err));
+            };
+            for write_error in failure.write_errors.iter().flatten() {
+                if let Some((id, result)) = report.results.get_mut(write_error.index) {
+                    let message = &write_error.message;
+                    *result = Err(
+                        match is_duplicate_id(write_error.code, message, &id_index) {
+                            true => DaoError::IdExists(id.clone()),
+                            false => DaoError::ServiceError(format!("MongoDB: {}", message)),
+                        },
+                    );
+                }
             }
-            Err(err) => {
+        }
+        log::trace!(
+            "Added {} of {} to {}",
+            report.succeeded().count(),
+            values.len(),
+            collection_name
+        );
+        Ok(report)
+    }
+
+    async fn update_many<T>(&self, filter: &Filter, update: Update) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        let collection_name = T::collection_name();
+        let update = update_document(collection_name, &update.into_operations()?)?;
+        // MongoDB rejects an empty update, so just count the matches
+        if update.is_empty() {
+            return self.count::<T>(filter).await;
+        }
+
+        let filter = filter_document(collection_name, filter)?;
+        let result = self
+            .database
+            .collection::<Document>(collection_name)
+            .update_many(filter, update, None)
+            .await
+            .map_err(|err| {
                 log::error!("Error updating {}: {:?}", collection_name, &err);
-                Err(DaoError::DatabaseError(err).into())
+                DaoError::DatabaseError(err)
+            })?;
+        log::trace!("Updated {} in {}", result.modified_count, collection_name);
+        Ok(result.matched_count)
+    }
+
+    async fn replace<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let id = value.collection_id();
+        let filter = doc! {T::collection_id_field(): &id};
+        match self
+            .database
+            .collection::<T>(collection_name)
+            .replace_one(filter, value, None)
+            .await
+        {
+            Ok(result) if result.matched_count == 0 => Err(DaoError::NotFound),
+            Ok(_) => {
+                log::trace!("Replaced {}: {}", collection_name, id);
+                Ok(())
+            }
+            Err(err) => {
+                log::error!("Error replacing {}: {:?}", collection_name, &err);
+                Err(DaoError::DatabaseError(err))
             }
         }
     }
 
-    pub async fn delete<T>(&self, id: &str) -> DaoResult<()>
+    async fn upsert<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        self.ensure_id_index::<T>().await?;
+
+        let id = value.collection_id();
+        let filter = doc! {T::collection_id_field(): &id};
+        let options = ReplaceOptions::builder().upsert(true).build();
+        match self
+            .database
+            .collection::<T>(collection_name)
+            .replace_one(filter, value, options)
+            .await
+        {
+            Ok(_) => {
+                log::trace!("Upserted {}: {}", collection_name, id);
+                Ok(())
+            }
+            Err(err) => {
+                log::error!("Error upserting {}: {:?}", collection_name, &err);
+                Err(DaoError::DatabaseError(err))
+            }
+        }
+    }
+
+    async fn delete<T>(&self, id: &str) -> DaoResult<bool>
     where
         T: Persistable,
     {
         let collection_name = T::collection_name();
         let filter = doc! {T::collection_id_field(): &id.to_string()};
-        self.database
-            .collection::<T>(collection_name)
+        let result = self
+            .database
+            .collection::<Document>(collection_name)
             .delete_one(filter, None)
             .await
             .map_err(|e| {
-                log::error!("Failed to delete: {}", e.to_string());
+                log::error!("Failed to delete: {}", e);
                 DaoError::DatabaseError(e)
             })?;
+        let deleted = result.deleted_count > 0;
+        log_delete(collection_name, T::collection_id_field(), id, deleted);
+        Ok(deleted)
+    }
+
+    async fn take<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let filter = doc! {T::collection_id_field(): &id.to_string()};
+        // Read and decode before deleting, so that an object which can't be decoded is never
+        // touched.  It is then deleted by its _id, which no other write can move it off.
+        let collection = self.database.collection::<Document>(collection_name);
+        let failed = |e| {
+            log::error!("Failed to delete: {}", e);
+            DaoError::DatabaseError(e)
+        };
+        let document = collection.find_one(filter, None).await.map_err(failed)?;
+        let Some(document) = document else {
+            log_delete(collection_name, T::collection_id_field(), id, false);
+            return Ok(None);
+        };
+        let key = document.get("_id").cloned();
+        let object = bson::from_document::<T>(document)
+            .map_err(|e| DaoError::deserialization::<T>(collection_name, e))?;
+        let deleted = match key {
+            Some(key) => {
+                collection
+                    .delete_one(doc! {"_id": key}, None)
+                    .await
+                    .map_err(failed)?
+                    .deleted_count
+                    > 0
+            }
+            None => false,
+        };
+        // Nothing deleted means another caller got there first
+        log_delete(collection_name, T::collection_id_field(), id, deleted);
+        Ok(deleted.then_some(object))
+    }
+
+    async fn delete_many<T>(&self, ids: &[&str]) -> DaoResult<BulkReport>
+    where
+        T: Persistable,
+    {
+        let collection_name = T::collection_name();
+        let id_field = T::collection_id_field();
+        let collection = self.database.collection::<Document>(collection_name);
+
+        // One delete per id, as a single delete only reports a total.  Finding the stored ids
+        // first would race with other writers.  A few run at a time, to spare the connection pool.
+        let keys = ids.iter().map(|id| id.to_string()).collect::<Vec<String>>();
+        let report = stream::iter(keys)
+            .map(|id| {
+                let collection = collection.clone();
+                async move {
+                    let result = match collection.delete_one(doc! {id_field: &id}, None).await {
+                        Ok(result) if result.deleted_count > 0 => Ok(()),
+                        Ok(_) => Err(DaoError::NotFound),
+                        Err(err) => {
+                            log::error!("Failed to delete {}: {}", id, err);
+                            Err(DaoError::DatabaseError(err))
+                        }
+                    };
+                    (id, result)
+                }
+            })
+            .buffered(DELETE_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<BulkReport>();
         log::trace!(
-            "Deleted {} - {}:{}",
-            collection_name,
-            T::collection_id_field(),
-            id
+            "Deleted {} of {} from {}",
+            report.succeeded().count(),
+            ids.len(),
+            collection_name
+        );
+        Ok(report)
+    }
+
+    async fn delete_where<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        let collection_name = T::collection_name();
+        let filter = filter_document(collection_name, filter)?;
+        let result = self
+            .database
+            .collection::<Document>(collection_name)
+            .delete_many(filter, None)
+            .await
+            .map_err(|e| {
+                log::error!("Failed to delete: {}", e);
+                DaoError::DatabaseError(e)
+            })?;
+        log::trace!("Deleted {} from {}", result.deleted_count, collection_name);
+        Ok(result.deleted_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch_options::{encode_page_token, page_token_filter};
+    use mongodb::bson::{oid::ObjectId, DateTime};
+
+    fn filter<K: Serialize + ?Sized>(key: &str, value: &K) -> Document {
+        field_filter("demo", key, value).unwrap()
+    }
+
+    #[test]
+    fn test_string_filter() {
+        assert_eq!(filter("name", "value"), doc! {"name": "value"});
+        assert_eq!(
+            filter("name", &String::from("value")),
+            doc! {"name": "value"}
+        );
+    }
+
+    #[test]
+    fn test_number_filter() {
+        assert_eq!(filter("count", &5i32), doc! {"count": Bson::Int32(5)});
+        assert_eq!(filter("count", &5i64), doc! {"count": Bson::Int64(5)});
+        assert_eq!(filter("count", &5u32), doc! {"count": Bson::Int64(5)});
+        assert_eq!(filter("ratio", &0.5f64), doc! {"ratio": Bson::Double(0.5)});
+    }
+
+    #[test]
+    fn test_bool_filter() {
+        assert_eq!(filter("flag", &true), doc! {"flag": Bson::Boolean(true)});
+        assert_eq!(filter("flag", &false), doc! {"flag": Bson::Boolean(false)});
+    }
+
+    #[test]
+    fn test_date_filter() {
+        let date = DateTime::from_millis(1_700_000_000_000);
+        assert_eq!(
+            filter("created", &date),
+            doc! {"created": Bson::DateTime(date)}
+        );
+    }
+
+    #[test]
+    fn test_object_id_filter() {
+        let oid = ObjectId::new();
+        assert_eq!(filter("_id", &oid), doc! {"_id": Bson::ObjectId(oid)});
+    }
+
+    #[test]
+    fn test_dotted_key_filter() {
+        assert_eq!(
+            filter("address.city", "Portland"),
+            doc! {"address.city": "Portland"}
         );
+    }
+
+    #[test]
+    fn test_embedded_document_filter() {
+        #[derive(Serialize)]
+        struct Address {
+            city: String,
+            zip: i32,
+        }
+        let address = Address {
+            city: "Portland".to_string(),
+            zip: 97201,
+        };
+        assert_eq!(
+            filter("address", &address),
+            doc! {"address": {"city": "Portland", "zip": Bson::Int32(97201)}}
+        );
+    }
+
+    #[test]
+    fn test_filter_document() {
+        let oid = ObjectId::new();
+        let date = DateTime::from_millis(1_700_000_000_000);
+        let filter = Filter::eq("_id", serde_json::to_value(oid).unwrap())
+            .and(Filter::gte("created", serde_json::to_value(date).unwrap()))
+            .and(Filter::is_in("count", [1, 2]))
+            .and(Filter::exists("name").or(Filter::regex("alias", "^a")))
+            .and(!Filter::ne("flag", true));
+
+        assert_eq!(
+            filter_document("demo", &filter).unwrap(),
+            doc! {"$and": [
+                {"_id": {"$eq": oid}},
+                {"created": {"$gte": date}},
+                {"count": {"$in": [1, 2]}},
+                {"$or": [
+                    {"name": {"$exists": true}},
+                    {"alias": {"$regex": "^a"}},
+                ]},
+                {"$nor": [{"flag": {"$ne": true}}]},
+            ]}
+        );
+    }
+
+    #[test]
+    fn test_date_page_token_filter() {
+        #[derive(Serialize)]
+        struct Event {
+            id: String,
+            created: DateTime,
+        }
+        let date = DateTime::from_millis(1_700_000_000_000);
+        let event = Event {
+            id: "a".to_string(),
+            created: date,
+        };
+        let sort = FetchOptions::new()
+            .sort_by("created", SortOrder::Descending)
+            .sort_with_id("id");
+        let token = encode_page_token("demo", &sort, &event).unwrap();
+        let filter = page_token_filter(&sort, &token).unwrap();
+
+        // Only dates sort after a date, and earlier ones come after it when descending
+        assert_eq!(
+            filter_document("demo", &filter).unwrap(),
+            doc! {"$or": [
+                {"$and": [{"$or": [
+                    {"$or": [{"created": {"$exists": false}}, {"created": {"$type": "null"}}]},
+                    {"created": {"$type": "number"}},
+                    {"created": {"$type": "string"}},
+                    {"created": {"$type": "object"}},
+                    {"created": {"$type": "array"}},
+                    {"created": {"$type": "objectId"}},
+                    {"created": {"$type": "bool"}},
+                    {"$and": [{"created": {"$type": "date"}}, {"created": {"$lt": date}}]},
+                ]}]},
+                {"$and": [
+                    {"$and": [{"created": {"$type": "date"}}, {"created": {"$eq": date}}]},
+                    {"$or": [
+                        {"id": {"$type": "object"}},
+                        {"id": {"$type": "array"}},
+                        {"id": {"$type": "objectId"}},
+                        {"id": {"$type": "bool"}},
+                        {"id": {"$type": "date"}},
+                        {"$and": [{"id": {"$type": "string"}}, {"id": {"$gt": "a"}}]},
+                    ]},
+                ]},
+            ]}
+        );
+    }
+
+    #[test]
+    fn test_empty_filter_document() {
+        assert_eq!(filter_document("demo", &Filter::all()).unwrap(), doc! {});
+        assert_eq!(
+            filter_document("demo", &Filter::Or(vec![])).unwrap(),
+            doc! {"$expr": false}
+        );
+    }
+
+    #[test]
+    fn test_update_document() {
+        let update = Update::new()
+            .set("name", "Rex")
+            .set("owner.name", "Sam")
+            .unset("tag")
+            .inc("age", 1)
+            .push("tricks", "sit")
+            .pull("tags", "dog")
+            .add_to_set("roles", "pet")
+            .into_operations()
+            .unwrap();
+        assert_eq!(
+            update_document("demo", &update).unwrap(),
+            doc! {
+                "$set": {"name": "Rex", "owner.name": "Sam"},
+                "$unset": {"tag": ""},
+                "$inc": {"age": 1},
+                "$push": {"tricks": "sit"},
+                "$pull": {"tags": "dog"},
+                "$addToSet": {"roles": "pet"},
+            }
+        );
+        assert!(update_document("demo", &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_duplicate_key() {
+        let write_error = |code: i32, index: &str| {
+            let message = format!(
+                "E11000 duplicate key error collection: db.demo index: {} dup key: {{ id: \"a\" }}",
+                index
+            );
+            let error = bson::from_document(doc! {"code": code, "errmsg": message}).unwrap();
+            mongodb::error::Error::from(ErrorKind::Write(WriteFailure::WriteError(error)))
+        };
+        assert!(is_duplicate_key(
+            &write_error(DUPLICATE_KEY, "id_1"),
+            "id_1"
+        ));
+        assert!(is_duplicate_key(
+            &write_error(DUPLICATE_KEY, "db.demo.$id_1"),
+            "id_1"
+        ));
+        assert!(is_duplicate_key(
+            &write_error(DUPLICATE_KEY, "_id_"),
+            "_id_"
+        ));
+        assert!(is_duplicate_key(
+            &write_error(DUPLICATE_KEY, "unique_id"),
+            "unique_id"
+        ));
+        assert!(!is_duplicate_key(
+            &write_error(DUPLICATE_KEY, "email_1"),
+            "id_1"
+        ));
+        assert!(!is_duplicate_key(
+            &write_error(DUPLICATE_KEY, "_id_"),
+            "id_1"
+        ));
+        assert!(!is_duplicate_key(&write_error(121, "id_1"), "id_1"));
+    }
+
+    #[test]
+    fn test_is_index_conflict() {
+        let command_error = |code: i32| {
+            let error = bson::from_document(doc! {"code": code, "errmsg": "conflict"}).unwrap();
+            mongodb::error::Error::from(ErrorKind::Command(error))
+        };
+        assert!(is_index_conflict(&command_error(85)));
+        assert!(is_index_conflict(&command_error(86)));
+        assert!(!is_index_conflict(&command_error(DUPLICATE_KEY)));
+    }
+
+    #[test]
+    fn test_find_options() {
+        let options = find_options(
+            &FetchOptions::new()
+                .sort_by("name", SortOrder::Ascending)
+                .sort_by("age", SortOrder::Descending)
+                .limit(10)
+                .skip(20),
+        );
+        assert_eq!(options.sort, Some(doc! {"name": 1, "age": -1}));
+        assert_eq!(options.limit, Some(10));
+        assert_eq!(options.skip, Some(20));
+        assert_eq!(find_options(&FetchOptions::default()).sort, None);
+    }
 
-        Ok(())
+    #[test]
+    fn test_unserializable_filter() {
+        let err = field_filter("demo", "count", &u64::MAX).unwrap_err();
+        assert!(matches!(err, DaoError::Serialization { location, .. } if location == "demo"));
     }
 }
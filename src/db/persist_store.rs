@@ -0,0 +1,235 @@
+/// Backend neutral persistence contract.
+/// [DataServices](crate::DataServices) only talks to the database through this trait, so any
+/// store that implements it can be swapped in for [DB](crate::DB).
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    fetch_options::{encode_page_token, page_token_filter},
+    BulkReport, DaoResult, FetchOptions, Filter, Page, Persistable, Update, DEFAULT_PAGE_SIZE,
+};
+
+/// Objects streamed by [PersistStore::fetch_stream].
+pub type DaoStream<T> = BoxStream<'static, DaoResult<T>>;
+
+/// Trace the outcome of deleting a single object.
+pub(crate) fn log_delete(collection_name: &str, id_field: &str, id: &str, deleted: bool) {
+    match deleted {
+        true => log::trace!("Deleted {} - {}:{}", collection_name, id_field, id),
+        false => log::trace!(
+            "Delete not found: {} - {}:{}",
+            collection_name,
+            id_field,
+            id
+        ),
+    }
+}
+
+#[async_trait]
+pub trait PersistStore: Clone + Send + Sync {
+    /// Add a new object.  Fails with [DaoError::IdExists](crate::DaoError::IdExists) if an object
+    /// with the same [Persistable::collection_id] is already stored.
+    async fn add<T>(&self, value: T) -> DaoResult<T>
+    where
+        T: core::fmt::Debug
+            + Clone
+            + Send
+            + Sync
+            + Unpin
+            + DeserializeOwned
+            + Serialize
+            + Persistable;
+
+    /// Fetch every object where `key` matches `value`.  If either is `None`, every object in
+    /// the collection is returned.  Returns `None` if nothing matched.
+    ///
+    /// Values are compared by type as well as value, and dotted keys (`"a.b.c"`) reach into
+    /// nested objects.
+    async fn fetch<T, K>(&self, key: Option<&str>, value: Option<K>) -> DaoResult<Option<Vec<T>>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+        K: Serialize + Send + Sync;
+
+    /// Fetch every object matching `filter`.  Returns `None` if nothing matched.
+    async fn fetch_where<T>(&self, filter: &Filter) -> DaoResult<Option<Vec<T>>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        self.fetch_with::<T>(filter, &FetchOptions::default()).await
+    }
+
+    /// Fetch the objects matching `filter`, sorted, skipped and limited according to `options`.
+    /// Returns `None` if nothing matched.
+    async fn fetch_with<T>(
+        &self,
+        filter: &Filter,
+        options: &FetchOptions,
+    ) -> DaoResult<Option<Vec<T>>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable;
+
+    /// Count the objects matching `filter`, without reading them.
+    async fn count<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable;
+
+    /// Check whether an object with the given [Persistable::collection_id] is stored, without
+    /// reading it.
+    async fn exists<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Persistable;
+
+    /// The distinct values of `field` across the objects matching `filter`, in no particular
+    /// order.  Arrays contribute each of their elements, and objects without the field are
+    /// skipped.
+    async fn distinct<T, V>(&self, field: &str, filter: &Filter) -> DaoResult<Vec<V>>
+    where
+        T: Persistable,
+        V: DeserializeOwned + Send;
+
+    /// Stream the objects matching `filter`, in the order given by `options`.  An error in the
+    /// middle of the stream is yielded as an item; stop reading to abandon the fetch.
+    ///
+    /// The default implementation fetches everything with [PersistStore::fetch_with] and then
+    /// streams it out.  Stores that can read lazily, such as [DB](crate::DB), override it so that
+    /// objects are only read as the stream is polled.
+    async fn fetch_stream<T>(
+        &self,
+        filter: &Filter,
+        options: &FetchOptions,
+    ) -> DaoResult<DaoStream<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable + 'static,
+    {
+        let result = self
+            .fetch_with::<T>(filter, options)
+            .await?
+            .unwrap_or_default();
+        Ok(stream::iter(result.into_iter().map(Ok)).boxed())
+    }
+
+    /// Fetch one page of the objects matching `filter`, in the order given by `options`.
+    /// [FetchOptions::limit] sets the page size, defaulting to [DEFAULT_PAGE_SIZE].
+    /// [FetchOptions::skip] only applies to the first page.
+    ///
+    /// Pass [Page::next] back as `token` to fetch the following page, keeping the same `filter`
+    /// and `options`.  The [Persistable::collection_id_field] is always used as the final sort
+    /// field, so that pages never overlap.
+    ///
+    /// Sort fields may be missing, null or of mixed types; see [FetchOptions] for their order.
+    /// MongoDB also orders objects and arrays by their contents, so don't page on such fields
+    /// there.
+    async fn fetch_page<T>(
+        &self,
+        filter: &Filter,
+        options: &FetchOptions,
+        token: Option<&str>,
+    ) -> DaoResult<Page<T>>
+    where
+        T: Clone + DeserializeOwned + Serialize + Unpin + Send + Sync + Persistable,
+    {
+        let sort = options.sort_with_id(T::collection_id_field());
+        let page_size = options.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        let (filter, skip) = match token {
+            Some(token) => (filter.clone().and(page_token_filter(&sort, token)?), None),
+            None => (filter.clone(), options.skip),
+        };
+        // Ask for one extra object, to find out whether there is another page
+        let query = FetchOptions {
+            sort: sort.clone(),
+            limit: Some(page_size + 1),
+            skip,
+        };
+
+        let mut items = self
+            .fetch_with::<T>(&filter, &query)
+            .await?
+            .unwrap_or_default();
+        let next = match items.len() as u64 > page_size {
+            true => {
+                items.truncate(page_size as usize);
+                match items.last() {
+                    Some(last) => Some(encode_page_token(T::collection_name(), &sort, last)?),
+                    None => None,
+                }
+            }
+            false => None,
+        };
+        Ok(Page { items, next })
+    }
+
+    /// Fetch a single object by the value of its [Persistable::collection_id_field].
+    async fn fetch_by_id<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable;
+
+    /// Set a single field on the object with the given id, and return the updated object.
+    /// Shorthand for [PersistStore::update_with] with a single [Update::set].
+    async fn update<T, K>(&self, id: &str, key: &str, value: K) -> DaoResult<T>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+        K: Clone + Serialize + Send + Sync,
+    {
+        self.update_with::<T>(id, Update::new().set(key, value))
+            .await
+    }
+
+    /// Apply every operation in `update` to the object with the given id, and return the updated
+    /// object.  The update and the read of the result happen as one atomic step.  Fails with
+    /// [DaoError::NotFound](crate::DaoError::NotFound) if no object has the id, and with
+    /// [DaoError::IdExists](crate::DaoError::IdExists) if it would move onto another object's id.
+    ///
+    /// If the updated object can't be decoded as `T`, the call fails with
+    /// [DaoError::Deserialization](crate::DaoError::Deserialization).  Most stores then leave the
+    /// object as it was, but [DB](crate::DB) has already stored the update.
+    async fn update_with<T>(&self, id: &str, update: Update) -> DaoResult<T>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable;
+
+    /// Add several new objects in one batch.  Objects whose [Persistable::collection_id] is
+    /// already stored are reported as [DaoError::IdExists](crate::DaoError::IdExists) in the
+    /// report, while the rest are still added.
+    async fn add_many<T>(&self, values: &[T]) -> DaoResult<BulkReport>
+    where
+        T: Serialize + Send + Sync + Persistable;
+
+    /// Apply `update` to every object matching `filter`, returning how many matched.
+    /// Each object is updated atomically, but the batch as a whole may not be.
+    async fn update_many<T>(&self, filter: &Filter, update: Update) -> DaoResult<u64>
+    where
+        T: Persistable;
+
+    /// Overwrite the stored object that has the same [Persistable::collection_id] as `value`.
+    /// Fails with [DaoError::NotFound](crate::DaoError::NotFound) if there isn't one.
+    async fn replace<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable;
+
+    /// Store `value`, overwriting any object with the same [Persistable::collection_id].
+    async fn upsert<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable;
+
+    /// Delete the object with the given id.  Returns whether there was one to delete.
+    async fn delete<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Persistable;
+
+    /// Delete the object with the given id, and return it.  Returns `None` if there was no
+    /// object to delete.
+    async fn take<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable;
+
+    /// Delete the objects with the given ids in one batch.  Ids with nothing stored are reported
+    /// as [DaoError::NotFound](crate::DaoError::NotFound).
+    async fn delete_many<T>(&self, ids: &[&str]) -> DaoResult<BulkReport>
+    where
+        T: Persistable;
+
+    /// Delete every object matching `filter`, returning how many were deleted.
+    async fn delete_where<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable;
+}
@@ -0,0 +1,978 @@
+/// SQLite persistence store.
+/// Each [Persistable] type is stored in a table named by [Persistable::collection_name], with a
+/// text primary key named by [Persistable::collection_id_field] and the serde serialized object
+/// in a JSON `body` column.  Field level lookups and updates go through the SQLite JSON1
+/// functions, so `"a.b"` style keys reach into nested objects.
+///
+/// SQLite calls are blocking, so they are run on the Tokio blocking thread pool.
+use async_trait::async_trait;
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use regex::Regex;
+use rusqlite::{
+    functions::{Context, FunctionFlags},
+    params,
+    types::{Value as SqlValue, ValueRef},
+    Connection, ErrorCode, OptionalExtension, Transaction, TransactionBehavior,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Number, Value};
+
+use crate::{
+    db::{distinct_values, document_id, log_delete},
+    fetch_options::rank,
+    filter::extended_value,
+    update::apply_update,
+    BoxedError, BulkReport, DaoError, DaoResult, DataServicesConfig, FetchOptions, Filter,
+    PersistStore, Persistable, SortOrder, Update, ValueType,
+};
+
+struct Inner {
+    connection: Connection,
+    /// Tables known to exist, so we only issue the DDL once per table
+    tables: HashSet<String>,
+}
+
+/// Clones share the same underlying connection.
+#[derive(Clone)]
+pub struct SqliteDB {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SqliteDB {
+    /// Open the database file named by [DataServicesConfig::db_uri].  A `sqlite://` prefix is
+    /// accepted, and `:memory:` opens a private in memory database.
+    pub async fn new(config: Arc<DataServicesConfig>) -> DaoResult<Self> {
+        let path = config
+            .db_uri
+            .strip_prefix("sqlite://")
+            .unwrap_or(&config.db_uri);
+        Self::open(path)
+    }
+
+    /// Open (or create) the database file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> DaoResult<Self> {
+        let connection = Connection::open(path)
+            .map_err(|_| DaoError::ServiceError("SQLite: failed to open database".to_string()))?;
+        Self::from_connection(connection)
+    }
+
+    /// Open a private in memory database.
+    pub fn open_in_memory() -> DaoResult<Self> {
+        let connection = Connection::open_in_memory()
+            .map_err(|_| DaoError::ServiceError("SQLite: failed to open database".to_string()))?;
+        Self::from_connection(connection)
+    }
+
+    fn from_connection(connection: Connection) -> DaoResult<Self> {
+        register_regexp(&connection)?;
+        register_sort_functions(&connection)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                connection,
+                tables: HashSet::new(),
+            })),
+        })
+    }
+
+    /// Run `f` against the table for `T` on the blocking thread pool, creating the table first if
+    /// needed.  `f` is handed the quoted table and id column names.
+    async fn call<T, R, F>(&self, f: F) -> DaoResult<R>
+    where
+        T: Persistable,
+        R: Send + 'static,
+        F: FnOnce(&Connection, &str, &str) -> DaoResult<R> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        let table = T::collection_name();
+        let id_field = T::collection_id_field();
+        tokio::task::spawn_blocking(move || {
+            let mut inner = inner
+                .lock()
+                .map_err(|_| DaoError::ServiceError("SQLite: lock poisoned".to_string()))?;
+            let table = quote_identifier(table);
+            let id_field = quote_identifier(id_field);
+            if !inner.tables.contains(&table) {
+                inner.connection.execute(
+                    &format!(
+                        "CREATE TABLE IF NOT EXISTS {} ({} TEXT PRIMARY KEY NOT NULL, body TEXT NOT NULL)",
+                        table, id_field
+                    ),
+                    [],
+                )?;
+                inner.tables.insert(table.clone());
+            }
+            f(&inner.connection, &table, &id_field)
+        })
+        .await
+        .map_err(|_| DaoError::ServiceError("SQLite: blocking task failed".to_string()))?
+    }
+}
+
+/// Quote an SQL identifier, so that any collection or field name is safe to use.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Turn a possibly dotted (`"a.b.c"`) key into a JSON path (`$."a"."b"."c"`).
+pub(crate) fn json_path(key: &str) -> String {
+    key.split('.').fold(String::from("$"), |path, part| {
+        format!("{}.\"{}\"", path, part.replace('"', "\\\""))
+    })
+}
+
+/// Build an SQL condition matching documents whose value at `path`, or an element of it, equals
+/// `value`.  JSON types are compared as well as values, so `1`, `true` and `"1"` are all distinct.
+/// A missing value equals `null`.
+pub(crate) fn json_eq(path: &str, value: &Value) -> (String, Vec<SqlValue>) {
+    let path = SqlValue::Text(path.to_string());
+    let (field, mut args) = value_eq(
+        "COALESCE(json_type(body, ?), 'null')",
+        "json_extract(body, ?)",
+        &path,
+        value,
+    );
+    let (element, element_args) = value_eq("type", "value", &SqlValue::Null, value);
+    args.extend([path.clone(), path]);
+    args.extend(element_args);
+    (
+        format!(
+            "({} OR (json_type(body, ?) = 'array' AND EXISTS (SELECT 1 FROM json_each(body, ?) WHERE {})))",
+            field, element
+        ),
+        args,
+    )
+}
+
+/// Build an SQL condition comparing a JSON value with `value`, given SQL expressions for its
+/// `json_type` and its SQL value.  Each `?` in those expressions is bound to `arg`.
+fn value_eq(
+    json_type: &str,
+    sql_value: &str,
+    arg: &SqlValue,
+    value: &Value,
+) -> (String, Vec<SqlValue>) {
+    let args = |expressions: &[&str]| -> Vec<SqlValue> {
+        expressions
+            .iter()
+            .flat_map(|expression| vec![arg.clone(); expression.matches('?').count()])
+            .collect()
+    };
+    match value {
+        Value::Null => (format!("{} = 'null'", json_type), args(&[json_type])),
+        Value::Bool(b) => {
+            let mut args = args(&[json_type]);
+            args.push(SqlValue::Text(b.to_string()));
+            (format!("{} = ?", json_type), args)
+        }
+        Value::Number(n) => {
+            let mut args = args(&[json_type, sql_value]);
+            args.push(sql_number(n));
+            (
+                format!("{} IN ('integer', 'real') AND {} = ?", json_type, sql_value),
+                args,
+            )
+        }
+        Value::String(s) => {
+            let mut args = args(&[json_type, sql_value]);
+            args.push(SqlValue::Text(s.clone()));
+            (
+                format!("{} = 'text' AND {} = ?", json_type, sql_value),
+                args,
+            )
+        }
+        Value::Array(_) | Value::Object(_) => {
+            let mut args = args(&[json_type, sql_value]);
+            args.push(SqlValue::Text(value.to_string()));
+            (
+                format!(
+                    "{} IN ('array', 'object') AND {} = json(?)",
+                    json_type, sql_value
+                ),
+                args,
+            )
+        }
+    }
+}
+
+fn sql_number(n: &Number) -> SqlValue {
+    match n.as_i64() {
+        Some(i) => SqlValue::Integer(i),
+        None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+    }
+}
+
+/// Build an SQL condition ordering the value at `path` against `value` with `op`.
+/// Only numbers, strings, dates and ObjectIds are ordered; anything else matches nothing.
+fn json_cmp(path: &str, op: &str, value: &Value) -> (String, Vec<SqlValue>) {
+    let path = SqlValue::Text(path.to_string());
+    let (types, value) = match value {
+        Value::Number(n) => ("('integer', 'real')", sql_number(n)),
+        Value::String(s) => ("('text')", SqlValue::Text(s.clone())),
+        _ => {
+            return match extended_value(value).and_then(|key| sort_key(&key)) {
+                Some(key) => (
+                    format!("value_type(body -> ?) = ? AND sort_key(body -> ?) {} ?", op),
+                    vec![
+                        path.clone(),
+                        SqlValue::Text(type_name(ValueType::of(value)).to_string()),
+                        path,
+                        key,
+                    ],
+                ),
+                None => ("0".to_string(), Vec::new()),
+            }
+        }
+    };
+    (
+        format!(
+            "json_type(body, ?) IN {} AND json_extract(body, ?) {} ?",
+            types, op
+        ),
+        vec![path.clone(), path, value],
+    )
+}
+
+/// Translate a [Filter] into an SQL condition over the `body` column, and its arguments.
+pub(crate) fn filter_sql(filter: &Filter) -> (String, Vec<SqlValue>) {
+    let join = |filters: &[Filter], op: &str, empty: &str| {
+        if filters.is_empty() {
+            return (empty.to_string(), Vec::new());
+        }
+        let (conditions, args): (Vec<String>, Vec<Vec<SqlValue>>) =
+            filters.iter().map(filter_sql).unzip();
+        (
+            format!("({})", conditions.join(&format!(" {} ", op))),
+            args.into_iter().flatten().collect(),
+        )
+    };
+    let negate = |(condition, args): (String, Vec<SqlValue>)| {
+        (format!("NOT COALESCE(({}), 0)", condition), args)
+    };
+
+    match filter {
+        Filter::Eq(key, value) => json_eq(&json_path(key), value),
+        Filter::Ne(key, value) => negate(json_eq(&json_path(key), value)),
+        Filter::Gt(key, value) => json_cmp(&json_path(key), ">", value),
+        Filter::Gte(key, value) => json_cmp(&json_path(key), ">=", value),
+        Filter::Lt(key, value) => json_cmp(&json_path(key), "<", value),
+        Filter::Lte(key, value) => json_cmp(&json_path(key), "<=", value),
+        Filter::In(key, values) => join(
+            &values
+                .iter()
+                .map(|value| Filter::Eq(key.clone(), value.clone()))
+                .collect::<Vec<Filter>>(),
+            "OR",
+            "0",
+        ),
+        Filter::Exists(key, exists) => (
+            format!(
+                "json_type(body, ?) IS {}NULL",
+                if *exists { "NOT " } else { "" }
+            ),
+            vec![SqlValue::Text(json_path(key))],
+        ),
+        Filter::Type(key, value_type) => (
+            "value_type(body -> ?) = ?".to_string(),
+            vec![
+                SqlValue::Text(json_path(key)),
+                SqlValue::Text(type_name(*value_type).to_string()),
+            ],
+        ),
+        Filter::Regex(key, pattern) => {
+            let path = SqlValue::Text(json_path(key));
+            (
+                "json_type(body, ?) = 'text' AND regexp(?, json_extract(body, ?))".to_string(),
+                vec![path.clone(), SqlValue::Text(pattern.clone()), path],
+            )
+        }
+        Filter::And(filters) => join(filters, "AND", "1"),
+        Filter::Or(filters) => join(filters, "OR", "0"),
+        Filter::Not(filter) => negate(filter_sql(filter)),
+    }
+}
+
+/// Translate sort fields into the leading terms of an SQL `ORDER BY`, each followed by a comma,
+/// and their arguments.  Values sort in the same order as in [MemoryDB](crate::MemoryDB): JSON
+/// nulls and missing fields first, then numbers, strings, objects, arrays, ObjectIds, booleans
+/// and dates.
+pub(crate) fn order_sql(sort: &[(String, SortOrder)]) -> (String, Vec<SqlValue>) {
+    let terms = sort
+        .iter()
+        .map(|(_, order)| {
+            let direction = match order {
+                SortOrder::Ascending => "ASC",
+                SortOrder::Descending => "DESC",
+            };
+            format!(
+                "sort_rank(body -> ?) {direction}, sort_key(body -> ?) {direction}, ",
+                direction = direction
+            )
+        })
+        .collect::<String>();
+    let args = sort
+        .iter()
+        .flat_map(|(key, _)| vec![SqlValue::Text(json_path(key)); 2])
+        .collect();
+    (terms, args)
+}
+
+/// The name `value_type()` gives each [ValueType].
+fn type_name(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::Null => "null",
+        ValueType::Number => "number",
+        ValueType::String => "string",
+        ValueType::Object => "object",
+        ValueType::Array => "array",
+        ValueType::ObjectId => "objectId",
+        ValueType::Bool => "bool",
+        ValueType::Date => "date",
+    }
+}
+
+/// The SQL value a JSON value sorts by among others of its type.  Objects and arrays have none,
+/// so they all sort level.
+fn sort_key(value: &Value) -> Option<SqlValue> {
+    match value {
+        Value::Number(n) => Some(sql_number(n)),
+        Value::String(s) => Some(SqlValue::Text(s.clone())),
+        Value::Bool(b) => Some(SqlValue::Integer(*b as i64)),
+        _ => sort_key(&extended_value(value)?),
+    }
+}
+
+/// Register `value_type(json)`, `sort_rank(json)` and `sort_key(json)`, which classify and order
+/// JSON text, such as `body -> ?`, the same way [MemoryDB](crate::MemoryDB) does.  A `NULL`
+/// argument is a missing value.
+fn register_sort_functions(connection: &Connection) -> rusqlite::Result<()> {
+    fn json_arg(ctx: &Context) -> rusqlite::Result<Option<Value>> {
+        match ctx.get_raw(0) {
+            ValueRef::Text(text) => serde_json::from_slice(text)
+                .map(Some)
+                .map_err(|e| rusqlite::Error::UserFunctionError(e.into())),
+            _ => Ok(None),
+        }
+    }
+
+    let flags = || FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    connection.create_scalar_function("value_type", 1, flags(), |ctx| {
+        Ok(json_arg(ctx)?.map(|value| type_name(ValueType::of(&value))))
+    })?;
+    connection.create_scalar_function("sort_rank", 1, flags(), |ctx| {
+        Ok(rank(json_arg(ctx)?.as_ref()) as i64)
+    })?;
+    connection.create_scalar_function("sort_key", 1, flags(), |ctx| {
+        Ok(json_arg(ctx)?.and_then(|value| sort_key(&value)))
+    })
+}
+
+/// Register `regexp(pattern, text)`, backed by the [regex] crate.  Compiled patterns are cached
+/// by SQLite for the life of the statement.
+fn register_regexp(connection: &Connection) -> rusqlite::Result<()> {
+    connection.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let regex: Arc<Regex> = ctx
+                .get_or_create_aux(0, |pattern| -> Result<_, BoxedError> {
+                    Ok(Regex::new(pattern.as_str()?)?)
+                })?;
+            Ok(match ctx.get_raw(1) {
+                ValueRef::Text(text) => std::str::from_utf8(text)
+                    .map(|text| regex.is_match(text))
+                    .unwrap_or(false),
+                _ => false,
+            })
+        },
+    )
+}
+
+fn is_constraint_violation(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(ErrorCode::ConstraintViolation)
+    )
+}
+
+#[async_trait]
+impl PersistStore for SqliteDB {
+    async fn add<T>(&self, value: T) -> DaoResult<T>
+    where
+        T: core::fmt::Debug
+            + Clone
+            + Send
+            + Sync
+            + Unpin
+            + DeserializeOwned
+            + Serialize
+            + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let id = value.collection_id();
+        let body = serde_json::to_string(&value)
+            .map_err(|e| DaoError::serialization::<T>(collection_name, e))?;
+
+        let inserted_id = id.clone();
+        let result = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                match con.execute(
+                    &format!("INSERT INTO {} ({}, body) VALUES (?, ?)", table, id_field),
+                    params![inserted_id, body],
+                ) {
+                    Ok(_) => Ok(()),
+                    Err(err) if is_constraint_violation(&err) => {
+                        Err(DaoError::IdExists(inserted_id))
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            })
+            .await;
+        match result {
+            Ok(_) => {
+                log::trace!("Added {}: {}", collection_name, id);
+                Ok(value)
+            }
+            Err(err) => {
+                log::error!("Error saving {}: {:?}", collection_name, &err);
+                Err(err)
+            }
+        }
+    }
+
+    async fn fetch<T, K>(&self, key: Option<&str>, value: Option<K>) -> DaoResult<Option<Vec<T>>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+        K: Serialize + Send + Sync,
+    {
+        let filter = match (key, value) {
+            (Some(k), Some(v)) => Filter::Eq(
+                k.to_string(),
+                serde_json::to_value(&v)
+                    .map_err(|e| DaoError::serialization::<K>(T::collection_name(), e))?,
+            ),
+            _ => Filter::all(),
+        };
+        self.fetch_where::<T>(&filter).await
+    }
+
+    async fn fetch_with<T>(
+        &self,
+        filter: &Filter,
+        options: &FetchOptions,
+    ) -> DaoResult<Option<Vec<T>>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        filter.validate()?;
+        let collection_name = T::collection_name();
+        let (condition, mut args) = filter_sql(filter);
+        let (order, order_args) = order_sql(&options.sort);
+        args.extend(order_args);
+        // SQLite needs a LIMIT to accept an OFFSET; a negative one means no limit
+        args.push(SqlValue::Integer(
+            options
+                .limit
+                .map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX)),
+        ));
+        args.push(SqlValue::Integer(
+            options
+                .skip
+                .map_or(0, |skip| i64::try_from(skip).unwrap_or(i64::MAX)),
+        ));
+
+        let bodies = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                let mut statement = con.prepare(&format!(
+                    "SELECT body FROM {} WHERE {} ORDER BY {}{} LIMIT ? OFFSET ?",
+                    table, condition, order, id_field
+                ))?;
+                let rows = statement
+                    .query_map(rusqlite::params_from_iter(args), |row| {
+                        row.get::<_, String>(0)
+                    })?
+                    .collect::<Result<Vec<String>, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+
+        let result = bodies
+            .iter()
+            .map(|body| {
+                serde_json::from_str::<T>(body)
+                    .map_err(|e| DaoError::deserialization::<T>(collection_name, e))
+            })
+            .collect::<DaoResult<Vec<T>>>()?;
+        if result.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(result))
+    }
+
+    async fn count<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        filter.validate()?;
+        let (condition, args) = filter_sql(filter);
+        let count = self
+            .call::<T, _, _>(move |con, table, _| {
+                Ok(con.query_row(
+                    &format!("SELECT COUNT(*) FROM {} WHERE {}", table, condition),
+                    rusqlite::params_from_iter(args),
+                    |row| row.get::<_, i64>(0),
+                )?)
+            })
+            .await?;
+        Ok(count as u64)
+    }
+
+    async fn exists<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Persistable,
+    {
+        let key = id.to_string();
+        self.call::<T, _, _>(move |con, table, id_field| {
+            Ok(con
+                .query_row(
+                    &format!("SELECT 1 FROM {} WHERE {} = ?", table, id_field),
+                    [key],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some())
+        })
+        .await
+    }
+
+    async fn distinct<T, V>(&self, field: &str, filter: &Filter) -> DaoResult<Vec<V>>
+    where
+        T: Persistable,
+        V: DeserializeOwned + Send,
+    {
+        filter.validate()?;
+        let collection_name = T::collection_name();
+        let (condition, mut args) = filter_sql(filter);
+        let path = json_path(field);
+        args.insert(0, SqlValue::Text(path.clone()));
+        args.push(SqlValue::Text(path));
+
+        // `->` keeps the JSON type of each value, which json_extract loses for booleans
+        let values = self
+            .call::<T, _, _>(move |con, table, _| {
+                let mut statement = con.prepare(&format!(
+                    "SELECT DISTINCT body -> ? FROM {} WHERE {} AND json_type(body, ?) IS NOT NULL",
+                    table, condition
+                ))?;
+                let rows = statement
+                    .query_map(rusqlite::params_from_iter(args), |row| {
+                        row.get::<_, String>(0)
+                    })?
+                    .collect::<Result<Vec<String>, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+
+        let values = values
+            .iter()
+            .map(|value| {
+                serde_json::from_str::<Value>(value)
+                    .map_err(|e| DaoError::deserialization::<Value>(collection_name, e))
+            })
+            .collect::<DaoResult<Vec<Value>>>()?;
+        distinct_values(values)
+            .into_iter()
+            .map(|value| {
+                serde_json::from_value::<V>(value)
+                    .map_err(|e| DaoError::deserialization::<V>(collection_name, e))
+            })
+            .collect()
+    }
+
+    async fn fetch_by_id<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let key = id.to_string();
+        let body = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                Ok(con
+                    .query_row(
+                        &format!("SELECT body FROM {} WHERE {} = ?", table, id_field),
+                        [key],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+
+        match body {
+            Some(body) => {
+                log::trace!(
+                    "Fetched {} - {}:{}",
+                    collection_name,
+                    T::collection_id_field(),
+                    id
+                );
+                let result = serde_json::from_str::<T>(&body)
+                    .map_err(|e| DaoError::deserialization::<T>(collection_name, e))?;
+                Ok(Some(result))
+            }
+            None => {
+                log::trace!(
+                    "Fetch not found: {} - {}:{}",
+                    collection_name,
+                    T::collection_id_field(),
+                    id
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    async fn update_with<T>(&self, id: &str, update: Update) -> DaoResult<T>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let operations = update.into_operations()?;
+        let old_id = id.to_string();
+        // T itself can't go to the blocking pool, but a plain function decoding it can.  The
+        // result is checked inside the transaction, so that an unreadable one is never written.
+        let decode: fn(&str) -> DaoResult<String> = |body| {
+            serde_json::from_str::<T>(body)
+                .map(|object| object.collection_id())
+                .map_err(|e| DaoError::deserialization::<T>(T::collection_name(), e))
+        };
+
+        // Read, apply and write back under an immediate transaction, so that no other
+        // connection can write in between.  Returning early drops the transaction, rolling it back.
+        let body = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                let transaction = Transaction::new_unchecked(con, TransactionBehavior::Immediate)?;
+                let body = transaction
+                    .query_row(
+                        &format!("SELECT body FROM {} WHERE {} = ?", table, id_field),
+                        [&old_id],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?;
+                let Some(body) = body else {
+                    return Ok(None);
+                };
+
+                let mut document = serde_json::from_str::<Value>(&body)
+                    .map_err(|e| DaoError::deserialization::<Value>(collection_name, e))?;
+                apply_update(&mut document, &operations)?;
+                let body = document.to_string();
+                let new_id = decode(&body)?;
+
+                // Keep the id column in step if the id itself was updated.
+                match transaction.execute(
+                    &format!(
+                        "UPDATE {} SET {} = ?, body = ? WHERE {} = ?",
+                        table, id_field, id_field
+                    ),
+                    params![new_id, body, old_id],
+                ) {
+                    Ok(_) => {}
+                    Err(err) if is_constraint_violation(&err) => {
+                        return Err(DaoError::IdExists(new_id))
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+                transaction.commit()?;
+                Ok(Some(body))
+            })
+            .await?;
+
+        let Some(body) = body else {
+            return Err(DaoError::NotFound);
+        };
+        log::trace!("Updated {}: {}", collection_name, id);
+        serde_json::from_str::<T>(&body)
+            .map_err(|e| DaoError::deserialization::<T>(collection_name, e))
+    }
+
+    async fn add_many<T>(&self, values: &[T]) -> DaoResult<BulkReport>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let bodies = values
+            .iter()
+            .map(|value| {
+                let body = serde_json::to_string(value)
+                    .map_err(|e| DaoError::serialization::<T>(collection_name, e));
+                (value.collection_id(), body)
+            })
+            .collect::<Vec<(String, DaoResult<String>)>>();
+
+        let report = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                let transaction = con.unchecked_transaction()?;
+                let mut statement = transaction.prepare(&format!(
+                    "INSERT INTO {} ({}, body) VALUES (?, ?)",
+                    table, id_field
+                ))?;
+                let mut report = BulkReport::default();
+                for (id, body) in bodies {
+                    let result = body.and_then(|body| match statement.execute(params![id, body]) {
+                        Ok(_) => Ok(()),
+                        Err(err) if is_constraint_violation(&err) => {
+                            Err(DaoError::IdExists(id.clone()))
+                        }
+                        Err(err) => Err(err.into()),
+                    });
+                    report.results.push((id, result));
+                }
+                drop(statement);
+                transaction.commit()?;
+                Ok(report)
+            })
+            .await?;
+        log::trace!(
+            "Added {} of {} to {}",
+            report.succeeded().count(),
+            values.len(),
+            collection_name
+        );
+        Ok(report)
+    }
+
+    async fn update_many<T>(&self, filter: &Filter, update: Update) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        filter.validate()?;
+        let collection_name = T::collection_name();
+        let raw_id_field = T::collection_id_field();
+        let operations = update.into_operations()?;
+        let (condition, args) = filter_sql(filter);
+
+        let count = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                let transaction = Transaction::new_unchecked(con, TransactionBehavior::Immediate)?;
+                let rows = transaction
+                    .prepare(&format!(
+                        "SELECT {}, body FROM {} WHERE {}",
+                        id_field, table, condition
+                    ))?
+                    .query_map(rusqlite::params_from_iter(args), |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<Result<Vec<(String, String)>, _>>()?;
+
+                for (id, body) in &rows {
+                    let mut document = serde_json::from_str::<Value>(body)
+                        .map_err(|e| DaoError::deserialization::<Value>(collection_name, e))?;
+                    apply_update(&mut document, &operations)?;
+                    // Keep the id column in step if the id itself was updated.
+                    let new_id = document_id(&document, raw_id_field).unwrap_or(id.clone());
+                    match transaction.execute(
+                        &format!(
+                            "UPDATE {} SET {} = ?, body = ? WHERE {} = ?",
+                            table, id_field, id_field
+                        ),
+                        params![new_id, document.to_string(), id],
+                    ) {
+                        Ok(_) => {}
+                        Err(err) if is_constraint_violation(&err) => {
+                            return Err(DaoError::IdExists(new_id))
+                        }
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                transaction.commit()?;
+                Ok(rows.len() as u64)
+            })
+            .await?;
+        log::trace!("Updated {} in {}", count, collection_name);
+        Ok(count)
+    }
+
+    async fn replace<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let id = value.collection_id();
+        let body = serde_json::to_string(value)
+            .map_err(|e| DaoError::serialization::<T>(collection_name, e))?;
+
+        let replaced_id = id.clone();
+        let changed = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                Ok(con.execute(
+                    &format!("UPDATE {} SET body = ? WHERE {} = ?", table, id_field),
+                    params![body, replaced_id],
+                )?)
+            })
+            .await?;
+        if changed == 0 {
+            return Err(DaoError::NotFound);
+        }
+        log::trace!("Replaced {}: {}", collection_name, id);
+        Ok(())
+    }
+
+    async fn upsert<T>(&self, value: &T) -> DaoResult<()>
+    where
+        T: Serialize + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let id = value.collection_id();
+        let body = serde_json::to_string(value)
+            .map_err(|e| DaoError::serialization::<T>(collection_name, e))?;
+
+        let upserted_id = id.clone();
+        self.call::<T, _, _>(move |con, table, id_field| {
+            con.execute(
+                &format!(
+                    "INSERT INTO {} ({}, body) VALUES (?, ?) ON CONFLICT ({}) DO UPDATE SET body = excluded.body",
+                    table, id_field, id_field
+                ),
+                params![upserted_id, body],
+            )?;
+            Ok(())
+        })
+        .await?;
+        log::trace!("Upserted {}: {}", collection_name, id);
+        Ok(())
+    }
+
+    async fn delete<T>(&self, id: &str) -> DaoResult<bool>
+    where
+        T: Persistable,
+    {
+        let collection_name = T::collection_name();
+        let key = id.to_string();
+        let deleted = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                Ok(con.execute(
+                    &format!("DELETE FROM {} WHERE {} = ?", table, id_field),
+                    [key],
+                )?)
+            })
+            .await
+            .inspect_err(|e| log::error!("Failed to delete: {}", e))?;
+        log_delete(collection_name, T::collection_id_field(), id, deleted > 0);
+        Ok(deleted > 0)
+    }
+
+    async fn take<T>(&self, id: &str) -> DaoResult<Option<T>>
+    where
+        T: Clone + DeserializeOwned + Unpin + Send + Sync + Persistable,
+    {
+        let collection_name = T::collection_name();
+        let key = id.to_string();
+        // As in update_with, the body is decoded inside the transaction, and only deleted once it
+        // has been.
+        let decode: fn(&str) -> DaoResult<()> = |body| {
+            serde_json::from_str::<T>(body)
+                .map(|_| ())
+                .map_err(|e| DaoError::deserialization::<T>(T::collection_name(), e))
+        };
+
+        let body = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                let transaction = Transaction::new_unchecked(con, TransactionBehavior::Immediate)?;
+                let body = transaction
+                    .query_row(
+                        &format!("SELECT body FROM {} WHERE {} = ?", table, id_field),
+                        [&key],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?;
+                let Some(body) = body else {
+                    return Ok(None);
+                };
+                decode(&body)?;
+                transaction.execute(
+                    &format!("DELETE FROM {} WHERE {} = ?", table, id_field),
+                    [&key],
+                )?;
+                transaction.commit()?;
+                Ok(Some(body))
+            })
+            .await
+            .inspect_err(|e| log::error!("Failed to delete: {}", e))?;
+        log_delete(
+            collection_name,
+            T::collection_id_field(),
+            id,
+            body.is_some(),
+        );
+        body.map(|body| {
+            serde_json::from_str::<T>(&body)
+                .map_err(|e| DaoError::deserialization::<T>(collection_name, e))
+        })
+        .transpose()
+    }
+
+    async fn delete_many<T>(&self, ids: &[&str]) -> DaoResult<BulkReport>
+    where
+        T: Persistable,
+    {
+        let collection_name = T::collection_name();
+        let keys = ids.iter().map(|id| id.to_string()).collect::<Vec<String>>();
+        let report = self
+            .call::<T, _, _>(move |con, table, id_field| {
+                let transaction = con.unchecked_transaction()?;
+                let mut statement = transaction
+                    .prepare(&format!("DELETE FROM {} WHERE {} = ?", table, id_field))?;
+                let mut report = BulkReport::default();
+                for key in keys {
+                    let result = match statement.execute([&key])? {
+                        0 => Err(DaoError::NotFound),
+                        _ => Ok(()),
+                    };
+                    report.results.push((key, result));
+                }
+                drop(statement);
+                transaction.commit()?;
+                Ok(report)
+            })
+            .await?;
+        log::trace!(
+            "Deleted {} of {} from {}",
+            report.succeeded().count(),
+            ids.len(),
+            collection_name
+        );
+        Ok(report)
+    }
+
+    async fn delete_where<T>(&self, filter: &Filter) -> DaoResult<u64>
+    where
+        T: Persistable,
+    {
+        filter.validate()?;
+        let collection_name = T::collection_name();
+        let (condition, args) = filter_sql(filter);
+        let count = self
+            .call::<T, _, _>(move |con, table, _| {
+                Ok(con.execute(
+                    &format!("DELETE FROM {} WHERE {}", table, condition),
+                    rusqlite::params_from_iter(args),
+                )?)
+            })
+            .await?;
+        log::trace!("Deleted {} from {}", count, collection_name);
+        Ok(count as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::conformance::conformance_tests;
+
+    conformance_tests!(SqliteDB::open_in_memory().unwrap());
+}
@@ -0,0 +1,322 @@
+/// Sorting, limits and pagination for multi-object fetches.
+///
+/// [FetchOptions] shape a single fetch.  [Page] is returned by
+/// [PersistStore::fetch_page](crate::PersistStore::fetch_page), which uses keyset pagination: each
+/// page carries an opaque `next` token that encodes where the page ended, so deep pages cost no
+/// more than the first one and concurrent inserts don't shift page boundaries.
+///
+/// Values of different types sort in the order MongoDB uses: missing fields and nulls first, then
+/// numbers, strings, objects, arrays, ObjectIds, booleans and dates.  ObjectIds and dates are
+/// recognised in their extended JSON form, the way they serialize outside BSON.
+///
+/// Example
+/// ```rust
+/// use swanky_persist::{FetchOptions, SortOrder};
+///
+/// let options = FetchOptions::new()
+///     .sort_by("last_name", SortOrder::Ascending)
+///     .sort_by("age", SortOrder::Descending)
+///     .limit(50);
+/// ```
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Serialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use crate::{db::lookup, filter::compare_values, DaoError, DaoResult, Filter, ValueType};
+
+/// Page size used by [PersistStore::fetch_page](crate::PersistStore::fetch_page) when
+/// [FetchOptions::limit] isn't set.
+pub const DEFAULT_PAGE_SIZE: u64 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FetchOptions {
+    /// Fields to sort by, most significant first.  Dotted keys reach into nested objects.
+    pub sort: Vec<(String, SortOrder)>,
+    /// Maximum number of objects to return.  A limit of 0 returns nothing.
+    pub limit: Option<u64>,
+    /// Number of matching objects to skip before returning any
+    pub skip: Option<u64>,
+}
+
+impl FetchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sort field, less significant than any already added.
+    pub fn sort_by(mut self, key: impl Into<String>, order: SortOrder) -> Self {
+        self.sort.push((key.into(), order));
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn skip(mut self, skip: u64) -> Self {
+        self.skip = Some(skip);
+        self
+    }
+
+    /// The sort fields, with `id_field` appended as a tie breaker so that the order is total.
+    pub(crate) fn sort_with_id(&self, id_field: &str) -> Vec<(String, SortOrder)> {
+        let mut sort = self.sort.clone();
+        if !sort.iter().any(|(key, _)| key == id_field) {
+            sort.push((id_field.to_string(), SortOrder::Ascending));
+        }
+        sort
+    }
+}
+
+/// One page of a paginated fetch.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Pass this back to fetch the following page.  `None` on the last page.
+    pub next: Option<String>,
+}
+
+/// Encode the position of `last` within `sort` as an opaque page token.
+pub(crate) fn encode_page_token<T: Serialize>(
+    collection_name: &str,
+    sort: &[(String, SortOrder)],
+    last: &T,
+) -> DaoResult<String> {
+    let document =
+        serde_json::to_value(last).map_err(|e| DaoError::serialization::<T>(collection_name, e))?;
+    let position = sort
+        .iter()
+        .map(|(key, _)| lookup(&document, key).cloned().unwrap_or(Value::Null))
+        .collect::<Vec<Value>>();
+    let position = serde_json::to_vec(&position)
+        .map_err(|e| DaoError::serialization::<Vec<Value>>(collection_name, e))?;
+    Ok(URL_SAFE_NO_PAD.encode(position))
+}
+
+/// Build a filter matching everything after the position encoded in `token`.
+pub(crate) fn page_token_filter(sort: &[(String, SortOrder)], token: &str) -> DaoResult<Filter> {
+    let invalid = || DaoError::InvalidPageToken(token.to_string());
+    let position = URL_SAFE_NO_PAD.decode(token).map_err(|_| invalid())?;
+    let position = serde_json::from_slice::<Vec<Value>>(&position).map_err(|_| invalid())?;
+    if position.len() != sort.len() {
+        return Err(invalid());
+    }
+
+    // (k1 after v1) OR (k1 level with v1 AND k2 after v2) OR ...
+    let mut after = Vec::new();
+    for (i, ((key, order), value)) in sort.iter().zip(&position).enumerate() {
+        let mut terms = sort[..i]
+            .iter()
+            .zip(&position)
+            .map(|((key, _), value)| level_with(key, value))
+            .collect::<Vec<Filter>>();
+        terms.push(sorts_after(key, *order, value));
+        after.push(Filter::And(terms));
+    }
+    Ok(Filter::Or(after))
+}
+
+/// Sort ranks, lowest first.  Values of different types sort by rank, and missing fields rank
+/// with nulls.
+const RANKS: [ValueType; 8] = [
+    ValueType::Null,
+    ValueType::Number,
+    ValueType::String,
+    ValueType::Object,
+    ValueType::Array,
+    ValueType::ObjectId,
+    ValueType::Bool,
+    ValueType::Date,
+];
+
+pub(crate) fn rank(value: Option<&Value>) -> usize {
+    let value_type = value.map_or(ValueType::Null, ValueType::of);
+    RANKS
+        .iter()
+        .position(|rank| *rank == value_type)
+        .unwrap_or_default()
+}
+
+/// Match a field holding a value of the given rank.
+fn ranked(key: &str, value_type: ValueType) -> Filter {
+    match value_type {
+        ValueType::Null => {
+            Filter::Exists(key.to_string(), false).or(Filter::is_type(key, ValueType::Null))
+        }
+        value_type => Filter::is_type(key, value_type),
+    }
+}
+
+/// Match a field that sorts level with `value`.  Objects and arrays all sort level with each
+/// other, as do nulls and missing fields.
+fn level_with(key: &str, value: &Value) -> Filter {
+    match ValueType::of(value) {
+        value_type @ (ValueType::Object | ValueType::Array | ValueType::Null) => {
+            ranked(key, value_type)
+        }
+        value_type => {
+            Filter::is_type(key, value_type).and(Filter::Eq(key.to_string(), value.clone()))
+        }
+    }
+}
+
+/// Match a field that sorts after `value` in the given order.
+fn sorts_after(key: &str, order: SortOrder, value: &Value) -> Filter {
+    let value_type = ValueType::of(value);
+    let rank = rank(Some(value));
+    let ordered = matches!(
+        value_type,
+        ValueType::Number | ValueType::String | ValueType::ObjectId | ValueType::Date
+    );
+    let (after, within) = match order {
+        SortOrder::Ascending => (
+            RANKS[rank + 1..].to_vec(),
+            match value {
+                _ if ordered => Some(Filter::Gt(key.to_string(), value.clone())),
+                Value::Bool(false) => Some(Filter::eq(key, true)),
+                _ => None,
+            },
+        ),
+        SortOrder::Descending => (
+            RANKS[..rank].to_vec(),
+            match value {
+                _ if ordered => Some(Filter::Lt(key.to_string(), value.clone())),
+                Value::Bool(true) => Some(Filter::eq(key, false)),
+                _ => None,
+            },
+        ),
+    };
+    let mut filters = after
+        .into_iter()
+        .map(|value_type| ranked(key, value_type))
+        .collect::<Vec<Filter>>();
+    if let Some(within) = within {
+        filters.push(Filter::is_type(key, value_type).and(within));
+    }
+    Filter::Or(filters)
+}
+
+/// Sort JSON documents in place.  Missing fields and nulls sort first, then numbers, strings,
+/// objects, arrays, ObjectIds, booleans and dates, in that order.  Objects and arrays sort level
+/// with others of their own type.
+pub(crate) fn sort_documents(documents: &mut [&Value], sort: &[(String, SortOrder)]) {
+    documents.sort_by(|a, b| {
+        sort.iter()
+            .map(|(key, order)| {
+                let (a, b) = (lookup(a, key), lookup(b, key));
+                let ordering = rank(a).cmp(&rank(b)).then_with(|| match (a, b) {
+                    (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+                    (Some(a), Some(b)) => compare_values(a, b).unwrap_or(Ordering::Equal),
+                    _ => Ordering::Equal,
+                });
+                match order {
+                    SortOrder::Ascending => ordering,
+                    SortOrder::Descending => ordering.reverse(),
+                }
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_sort_with_id() {
+        let options = FetchOptions::new().sort_by("name", SortOrder::Descending);
+        assert_eq!(
+            options.sort_with_id("id"),
+            vec![
+                ("name".to_string(), SortOrder::Descending),
+                ("id".to_string(), SortOrder::Ascending)
+            ]
+        );
+        let options = options.sort_by("id", SortOrder::Descending);
+        assert_eq!(options.sort_with_id("id"), options.sort);
+    }
+
+    #[test]
+    fn test_page_token_round_trip() {
+        let docs = [
+            json!({"id": "a"}),
+            json!({"id": "b", "n": null}),
+            json!({"id": "c", "n": 2}),
+            json!({"id": "d", "n": 1.5}),
+            json!({"id": "e", "n": "x"}),
+            json!({"id": "f", "n": {"x": 1}}),
+            json!({"id": "g", "n": {"y": 1}}),
+            json!({"id": "h", "n": [1]}),
+            json!({"id": "i", "n": true}),
+            json!({"id": "j", "n": false}),
+            json!({"id": "k", "n": 2}),
+            json!({"id": "l", "n": {"$date": {"$numberLong": "2"}}}),
+            json!({"id": "m", "n": {"$date": {"$numberLong": "1"}}}),
+            json!({"id": "n", "n": {"$oid": "65a000000000000000000001"}}),
+        ];
+        for order in [SortOrder::Ascending, SortOrder::Descending] {
+            let sort = FetchOptions::new().sort_by("n", order).sort_with_id("id");
+            let mut sorted = docs.iter().collect::<Vec<&Value>>();
+            sort_documents(&mut sorted, &sort);
+
+            // Every position matches exactly the documents sorted after it
+            for (i, last) in sorted.iter().enumerate() {
+                let token = encode_page_token("demo", &sort, last).unwrap();
+                let filter = page_token_filter(&sort, &token).unwrap();
+                let matcher = filter.matcher().unwrap();
+                let after = sorted
+                    .iter()
+                    .filter(|doc| matcher.matches(doc))
+                    .copied()
+                    .collect::<Vec<&Value>>();
+                assert_eq!(after, sorted[i + 1..], "after {} {:?}", last, order);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sort_documents() {
+        let docs = [
+            json!({"id": "a", "n": 2}),
+            json!({"id": "b", "n": "x"}),
+            json!({"id": "c"}),
+            json!({"id": "d", "n": 1}),
+            json!({"id": "e", "n": 2}),
+        ];
+        let mut sorted = docs.iter().collect::<Vec<&Value>>();
+        sort_documents(
+            &mut sorted,
+            &[
+                ("n".to_string(), SortOrder::Ascending),
+                ("id".to_string(), SortOrder::Descending),
+            ],
+        );
+        let ids = sorted
+            .iter()
+            .map(|d| d["id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["c", "d", "e", "a", "b"]);
+    }
+
+    #[test]
+    fn test_invalid_page_token() {
+        let sort = vec![("id".to_string(), SortOrder::Ascending)];
+        assert!(matches!(
+            page_token_filter(&sort, "not a token"),
+            Err(DaoError::InvalidPageToken(_))
+        ));
+        let token = encode_page_token("demo", &sort, &json!({"id": "a"})).unwrap();
+        let longer = vec![sort[0].clone(), sort[0].clone()];
+        assert!(page_token_filter(&longer, &token).is_err());
+    }
+}
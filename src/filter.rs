@@ -0,0 +1,437 @@
+/// Backend neutral query filters, for use with [PersistStore::fetch_where](crate::PersistStore::fetch_where).
+///
+/// Values are held as JSON.  Anything that converts into a [serde_json::Value] can be used
+/// directly; other [serde::Serialize] types can be converted with [serde_json::to_value].
+/// MongoDB specific types such as ObjectIds and dates serialize to MongoDB extended JSON, and
+/// are turned back into their native BSON types by [DB](crate::DB).
+///
+/// Keys may be dotted (`"a.b.c"`) to reach into nested objects.
+///
+/// Every backend follows MongoDB where it can:
+/// - [Filter::Eq], [Filter::Ne] and [Filter::In] also look at the elements of array fields, so
+///   `Filter::eq("tags", "dog")` matches `{"tags": ["dog", "cat"]}`.
+/// - Equality with `null` matches missing fields as well as `null` ones.
+///
+/// Some differences remain:
+/// - Ordering ([Filter::Gt] and friends) only applies to numbers, strings, dates and ObjectIds
+///   outside MongoDB.  Dates only order against dates in the same extended JSON form.
+/// - Dotted keys do not reach into arrays of objects outside MongoDB.
+/// - Regular expressions are checked with the [regex] crate's syntax, but MongoDB runs them as
+///   PCRE.  Stick to the syntax the two share.
+///
+/// Example
+/// ```rust
+/// use swanky_persist::Filter;
+///
+/// let filter = Filter::eq("status", "active")
+///     .and(Filter::gte("age", 21).or(!Filter::exists("guardian")))
+///     .and(Filter::is_in("role", ["admin", "editor"]));
+/// ```
+use regex::Regex;
+use serde_json::Value;
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::{db::lookup, DaoError, DaoResult};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    /// The field, or an element of it, equals the value
+    Eq(String, Value),
+    /// Neither the field nor any element of it equals the value
+    Ne(String, Value),
+    /// The field is greater than the value
+    Gt(String, Value),
+    /// The field is greater than or equal to the value
+    Gte(String, Value),
+    /// The field is less than the value
+    Lt(String, Value),
+    /// The field is less than or equal to the value
+    Lte(String, Value),
+    /// The field, or an element of it, equals one of the values
+    In(String, Vec<Value>),
+    /// The field is (`true`) or is not (`false`) present
+    Exists(String, bool),
+    /// The field is a string matching the regular expression
+    Regex(String, String),
+    /// The field holds a value of the given type.  In MongoDB an array field also matches the
+    /// type of its elements.
+    Type(String, ValueType),
+    /// Every filter matches.  An empty list matches everything.
+    And(Vec<Filter>),
+    /// At least one filter matches.  An empty list matches nothing.
+    Or(Vec<Filter>),
+    /// The filter does not match
+    Not(Box<Filter>),
+}
+
+/// JSON value types, for [Filter::Type].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    Null,
+    Number,
+    String,
+    /// Any object other than an extended JSON ObjectId or date
+    Object,
+    Array,
+    /// An extended JSON ObjectId, `{"$oid": "..."}`
+    ObjectId,
+    Bool,
+    /// An extended JSON date, `{"$date": ...}`
+    Date,
+}
+
+impl ValueType {
+    pub(crate) fn of(value: &Value) -> Self {
+        match value {
+            Value::Null => Self::Null,
+            Value::Number(_) => Self::Number,
+            Value::String(_) => Self::String,
+            Value::Object(map) if map.len() == 1 => match map.iter().next() {
+                Some((key, Value::String(_))) if key == "$oid" => Self::ObjectId,
+                Some((key, _)) if key == "$date" => Self::Date,
+                _ => Self::Object,
+            },
+            Value::Object(_) => Self::Object,
+            Value::Array(_) => Self::Array,
+            Value::Bool(_) => Self::Bool,
+        }
+    }
+}
+
+impl Filter {
+    pub fn eq(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::Eq(key.into(), value.into())
+    }
+
+    pub fn ne(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::Ne(key.into(), value.into())
+    }
+
+    pub fn gt(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::Gt(key.into(), value.into())
+    }
+
+    pub fn gte(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::Gte(key.into(), value.into())
+    }
+
+    pub fn lt(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::Lt(key.into(), value.into())
+    }
+
+    pub fn lte(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Self::Lte(key.into(), value.into())
+    }
+
+    pub fn is_in<V: Into<Value>>(
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Self {
+        Self::In(key.into(), values.into_iter().map(Into::into).collect())
+    }
+
+    pub fn exists(key: impl Into<String>) -> Self {
+        Self::Exists(key.into(), true)
+    }
+
+    pub fn regex(key: impl Into<String>, pattern: impl Into<String>) -> Self {
+        Self::Regex(key.into(), pattern.into())
+    }
+
+    pub fn is_type(key: impl Into<String>, value_type: ValueType) -> Self {
+        Self::Type(key.into(), value_type)
+    }
+
+    /// Matches everything.
+    pub fn all() -> Self {
+        Self::And(Vec::new())
+    }
+
+    /// Both this filter and `other` match.
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Self::And(mut filters) => {
+                filters.push(other);
+                Self::And(filters)
+            }
+            filter => Self::And(vec![filter, other]),
+        }
+    }
+
+    /// Either this filter or `other` matches.
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Self::Or(mut filters) => {
+                filters.push(other);
+                Self::Or(filters)
+            }
+            filter => Self::Or(vec![filter, other]),
+        }
+    }
+
+    /// Check every regular expression in the filter, so that evaluation can't fail part way.
+    #[cfg(any(feature = "sqlite", test))]
+    pub(crate) fn validate(&self) -> DaoResult<()> {
+        self.matcher().map(|_| ())
+    }
+
+    /// Compile the filter for evaluation against JSON documents.
+    pub(crate) fn matcher(&self) -> DaoResult<Matcher<'_>> {
+        let mut regexes = HashMap::new();
+        self.compile(&mut regexes)?;
+        Ok(Matcher {
+            filter: self,
+            regexes,
+        })
+    }
+
+    fn compile<'a>(&'a self, regexes: &mut HashMap<&'a str, Regex>) -> DaoResult<()> {
+        match self {
+            Self::Regex(_, pattern) => {
+                if !regexes.contains_key(pattern.as_str()) {
+                    let regex = Regex::new(pattern).map_err(|e| {
+                        DaoError::ServiceError(format!("Invalid filter pattern {}: {}", pattern, e))
+                    })?;
+                    regexes.insert(pattern, regex);
+                }
+                Ok(())
+            }
+            Self::And(filters) | Self::Or(filters) => filters
+                .iter()
+                .try_for_each(|filter| filter.compile(regexes)),
+            Self::Not(filter) => filter.compile(regexes),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A [Filter] with its regular expressions compiled, ready to evaluate against many documents.
+pub(crate) struct Matcher<'a> {
+    filter: &'a Filter,
+    regexes: HashMap<&'a str, Regex>,
+}
+
+impl Matcher<'_> {
+    /// Evaluate the filter against a JSON document.
+    pub(crate) fn matches(&self, document: &Value) -> bool {
+        self.evaluate(self.filter, document)
+    }
+
+    fn evaluate(&self, filter: &Filter, document: &Value) -> bool {
+        let compare = |key: &str, value: &Value, accept: fn(Ordering) -> bool| {
+            lookup(document, key)
+                .and_then(|field| compare_values(field, value))
+                .is_some_and(accept)
+        };
+        match filter {
+            Filter::Eq(key, value) => field_equal(lookup(document, key), value),
+            Filter::Ne(key, value) => !field_equal(lookup(document, key), value),
+            Filter::Gt(key, value) => compare(key, value, Ordering::is_gt),
+            Filter::Gte(key, value) => compare(key, value, Ordering::is_ge),
+            Filter::Lt(key, value) => compare(key, value, Ordering::is_lt),
+            Filter::Lte(key, value) => compare(key, value, Ordering::is_le),
+            Filter::In(key, values) => {
+                let field = lookup(document, key);
+                values.iter().any(|value| field_equal(field, value))
+            }
+            Filter::Exists(key, exists) => lookup(document, key).is_some() == *exists,
+            Filter::Regex(key, pattern) => match lookup(document, key) {
+                Some(Value::String(field)) => self
+                    .regexes
+                    .get(pattern.as_str())
+                    .is_some_and(|regex| regex.is_match(field)),
+                _ => false,
+            },
+            Filter::Type(key, value_type) => {
+                lookup(document, key).is_some_and(|field| ValueType::of(field) == *value_type)
+            }
+            Filter::And(filters) => filters.iter().all(|f| self.evaluate(f, document)),
+            Filter::Or(filters) => filters.iter().any(|f| self.evaluate(f, document)),
+            Filter::Not(filter) => !self.evaluate(filter, document),
+        }
+    }
+}
+
+/// Whether a possibly missing field, or one of its elements, equals `value`.  A missing field
+/// equals `null`.
+fn field_equal(field: Option<&Value>, value: &Value) -> bool {
+    match field {
+        None => value.is_null(),
+        Some(field @ Value::Array(elements)) => {
+            equal(field, value) || elements.iter().any(|element| equal(element, value))
+        }
+        Some(field) => equal(field, value),
+    }
+}
+
+impl std::ops::Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Self::Output {
+        match self {
+            Self::Not(filter) => *filter,
+            filter => Self::Not(Box::new(filter)),
+        }
+    }
+}
+
+/// Compare two JSON values, treating numbers as equal by value (so `2 == 2.0`).
+pub(crate) fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(_), Value::Number(_)) => compare_values(a, b) == Some(Ordering::Equal),
+        _ => a == b,
+    }
+}
+
+/// Order two JSON values of the same kind.  Numbers compare with numbers, strings with strings,
+/// dates with dates and ObjectIds with ObjectIds; anything else is unordered.
+pub(crate) fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Object(_), Value::Object(_)) if ValueType::of(a) == ValueType::of(b) => {
+            compare_values(&extended_value(a)?, &extended_value(b)?)
+        }
+        _ => None,
+    }
+}
+
+/// The orderable value inside an extended JSON ObjectId or date: the ObjectId's hex string, or
+/// the date's milliseconds since the epoch.  Relaxed dates give their ISO 8601 string.
+pub(crate) fn extended_value(value: &Value) -> Option<Value> {
+    let Value::Object(map) = value else {
+        return None;
+    };
+    match ValueType::of(value) {
+        ValueType::ObjectId => map.get("$oid").cloned(),
+        ValueType::Date => match map.get("$date")? {
+            Value::Object(date) => date
+                .get("$numberLong")?
+                .as_str()?
+                .parse::<i64>()
+                .ok()
+                .map(Value::from),
+            date => Some(date.clone()),
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    impl Filter {
+        fn matches(&self, document: &Value) -> bool {
+            self.matcher().unwrap().matches(document)
+        }
+    }
+
+    fn document() -> Value {
+        json!({
+            "name": "Rex",
+            "age": 4,
+            "weight": 30.5,
+            "good": true,
+            "owner": {"name": "Sam"},
+            "tags": ["dog", "friendly"],
+        })
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let doc = document();
+        assert!(Filter::eq("name", "Rex").matches(&doc));
+        assert!(!Filter::eq("age", "4").matches(&doc));
+        assert!(Filter::ne("name", "Fido").matches(&doc));
+        assert!(Filter::ne("missing", "Fido").matches(&doc));
+        assert!(Filter::gt("age", 3).matches(&doc));
+        assert!(!Filter::gt("age", 4).matches(&doc));
+        assert!(Filter::gte("age", 4).matches(&doc));
+        assert!(Filter::eq("age", 4.0).matches(&doc));
+        assert!(Filter::lt("weight", 31).matches(&doc));
+        assert!(Filter::lte("weight", 30.5).matches(&doc));
+        assert!(!Filter::lt("name", 3).matches(&doc));
+        assert!(Filter::gt("name", "Max").matches(&doc));
+    }
+
+    #[test]
+    fn test_nested_and_membership() {
+        let doc = document();
+        assert!(Filter::eq("owner.name", "Sam").matches(&doc));
+        assert!(Filter::is_in("age", [1, 4, 9]).matches(&doc));
+        assert!(!Filter::is_in("age", Vec::<i32>::new()).matches(&doc));
+        assert!(Filter::exists("owner.name").matches(&doc));
+        assert!(Filter::Exists("owner.age".into(), false).matches(&doc));
+        assert!(Filter::regex("name", "^R.x$").matches(&doc));
+        assert!(!Filter::regex("age", "4").matches(&doc));
+        assert!(Filter::is_type("tags", ValueType::Array).matches(&doc));
+        assert!(!Filter::is_type("tags", ValueType::String).matches(&doc));
+        assert!(!Filter::is_type("missing", ValueType::Null).matches(&doc));
+    }
+
+    #[test]
+    fn test_extended_json() {
+        let date = |millis: i64| json!({"$date": {"$numberLong": millis.to_string()}});
+        let doc = json!({
+            "created": date(1_700_000_000_000),
+            "_id": {"$oid": "65a000000000000000000002"},
+            "owner": {"$date": 1, "name": "Sam"},
+        });
+        assert!(Filter::is_type("created", ValueType::Date).matches(&doc));
+        assert!(Filter::is_type("_id", ValueType::ObjectId).matches(&doc));
+        assert!(Filter::is_type("owner", ValueType::Object).matches(&doc));
+        assert!(!Filter::is_type("created", ValueType::Object).matches(&doc));
+        assert!(Filter::gt("created", date(999_999_999_999)).matches(&doc));
+        assert!(!Filter::gt("created", date(1_700_000_000_000)).matches(&doc));
+        assert!(Filter::lt("_id", json!({"$oid": "65a000000000000000000010"})).matches(&doc));
+        assert!(!Filter::lt("created", json!({"$oid": "65a000000000000000000010"})).matches(&doc));
+    }
+
+    #[test]
+    fn test_arrays_and_nulls() {
+        let doc = document();
+        assert!(Filter::eq("tags", "dog").matches(&doc));
+        assert!(Filter::eq("tags", json!(["dog", "friendly"])).matches(&doc));
+        assert!(!Filter::eq("tags", "cat").matches(&doc));
+        assert!(!Filter::ne("tags", "dog").matches(&doc));
+        assert!(Filter::is_in("tags", ["cat", "friendly"]).matches(&doc));
+
+        assert!(Filter::eq("missing", Value::Null).matches(&doc));
+        assert!(Filter::eq("nothing", Value::Null).matches(&json!({"nothing": null})));
+        assert!(!Filter::eq("name", Value::Null).matches(&doc));
+        assert!(!Filter::ne("missing", Value::Null).matches(&doc));
+        assert!(Filter::is_in("missing", [Value::Null]).matches(&doc));
+    }
+
+    #[test]
+    fn test_logic() {
+        let doc = document();
+        assert!(Filter::all().matches(&doc));
+        assert!(!Filter::Or(vec![]).matches(&doc));
+        assert!(Filter::eq("good", true)
+            .and(Filter::gt("age", 1))
+            .matches(&doc));
+        assert!(!Filter::eq("good", false)
+            .and(Filter::gt("age", 1))
+            .matches(&doc));
+        assert!(Filter::eq("good", false)
+            .or(Filter::gt("age", 1))
+            .matches(&doc));
+        assert!((!Filter::eq("name", "Fido")).matches(&doc));
+        assert_eq!(!!Filter::eq("name", "Rex"), Filter::eq("name", "Rex"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Filter::regex("name", "^R").validate().is_ok());
+        assert!((!Filter::regex("name", "(")).validate().is_err());
+
+        let filter = Filter::regex("name", "^R").or(Filter::regex("owner.name", "^R"));
+        assert_eq!(filter.matcher().unwrap().regexes.len(), 1);
+    }
+}
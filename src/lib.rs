@@ -55,9 +55,13 @@
 //!  let data_services_config =
 //!      Arc::new(DataServicesConfig::new().expect("Failed to create DataServicesConfig"));
 //!
-//!  let services = DataServices::new(data_services_config.clone())
-//!     .await
-//!      .expect("Services failed. Did you remember to start them?");
+//!  // DataServices::new connects to MongoDB and Redis.  Here everything is kept in process
+//!  // with MemoryCache and MemoryDB instead, so no services need to be running.
+//!  let services = DataServices::with_stores(
+//!      data_services_config.clone(),
+//!      MemoryCache::default(),
+//!      MemoryDB::new(),
+//!  );
 //!
 //!  // Insert the instance and varify it's there
 //!  let result = services
@@ -67,7 +71,7 @@
 //!  assert_eq!(&demo_struct, &result);
 //!
 //!  let result = services
-//!      .fetch_cached::<DemoStruct>(&result.id)
+//!      .fetch_by_id_cached::<DemoStruct>(&result.id)
 //!      .await
 //!      .expect("Failed to fetch the object")
 //!      .unwrap();
@@ -81,8 +85,7 @@
 //!          "Updated description of this obj".to_string(),
 //!      )
 //!      .await
-//!      .expect("Failed to update the object")
-//!     .unwrap();
+//!      .expect("Failed to update the object");
 //!  assert_eq!(&new_obj.description, "Updated description of this obj");
 //!
 // Delete the object and verify it's gone
@@ -92,7 +95,7 @@
 //!      .expect("Failed to delete object");
 //!
 //!  let result = services
-//!      .fetch_cached::<DemoStruct>(&new_obj.collection_id())
+//!      .fetch_by_id_cached::<DemoStruct>(&new_obj.collection_id())
 //!      .await
 //!     .expect("Failed to fetch object again");
 //!  assert!(result.is_none());
@@ -113,27 +116,40 @@
 //!  }
 //! ```
 
-pub use cache::redis_cache::*;
+pub use bulk::*;
+pub use cache::*;
 pub use dao_error::*;
 pub use data_services::*;
 pub use data_services_config::*;
-pub use db::mongo_db::*;
+pub use db::*;
+pub use fetch_options::*;
+pub use filter::*;
 pub use swanky_persist_cacheable::*;
 pub use swanky_persist_persistable::*;
+pub use update::*;
 
+mod bulk;
 mod cache;
 mod dao_error;
 mod data_services;
 mod data_services_config;
 mod db;
+mod fetch_options;
+mod filter;
+mod single_flight;
+mod update;
 
+#[cfg(feature = "derive")]
 #[allow(unused_imports)]
 #[macro_use]
 extern crate swanky_persist_derive_persist;
 
+#[cfg(feature = "derive")]
 #[allow(unused_imports)]
 #[macro_use]
 extern crate swanky_persist_derive_cache;
 
+#[cfg(feature = "derive")]
 pub use swanky_persist_derive_cache::Cache;
+#[cfg(feature = "derive")]
 pub use swanky_persist_derive_persist::Persist;
@@ -0,0 +1,145 @@
+/// Per key single flight loads, used to make sure only one task in the process loads a given
+/// cache key at a time, and that the tasks waiting on it share its result.
+///
+/// Results are handed to the waiting tasks as JSON, so the loaded type needn't be `'static`.  If
+/// the load fails, or its result can't be shared, each waiting task loads for itself instead.
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::{FutureExt, Shared};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use crate::{DaoError, DaoResult};
+
+type Flight = Shared<oneshot::Receiver<Arc<Value>>>;
+type Flights = Arc<Mutex<HashMap<String, Flight>>>;
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SingleFlight {
+    flights: Flights,
+}
+
+/// Ends a flight when dropped, even if the load is abandoned part way.
+struct Landing {
+    key: String,
+    flights: Flights,
+}
+
+impl SingleFlight {
+    /// Run `load` for `key`, unless another task is already loading it, in which case wait for and
+    /// share that task's result.
+    pub(crate) async fn run<T, F, Fut>(&self, key: &str, load: F) -> DaoResult<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = DaoResult<T>>,
+    {
+        let (flight, sender) = {
+            let mut flights = self.flights.lock().unwrap_or_else(|e| e.into_inner());
+            match flights.get(key) {
+                Some(flight) => (Some(flight.clone()), None),
+                None => {
+                    let (sender, receiver) = oneshot::channel();
+                    flights.insert(key.to_string(), receiver.shared());
+                    (None, Some(sender))
+                }
+            }
+        };
+
+        if let Some(flight) = flight {
+            return match flight.await {
+                Ok(value) => {
+                    T::deserialize(&*value).map_err(|e| DaoError::deserialization::<T>(key, e))
+                }
+                // The loader failed or gave up
+                Err(_) => load().await,
+            };
+        }
+
+        let landing = Landing {
+            key: key.to_string(),
+            flights: self.flights.clone(),
+        };
+        let result = load().await;
+        drop(landing);
+        if let (Ok(value), Some(sender)) = (&result, sender) {
+            if let Ok(value) = serde_json::to_value(value) {
+                let _ = sender.send(Arc::new(value));
+            }
+        }
+        result
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.flights.lock().unwrap().len()
+    }
+}
+
+impl Drop for Landing {
+    fn drop(&mut self) {
+        let mut flights = self.flights.lock().unwrap_or_else(|e| e.into_inner());
+        flights.remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_one_load_per_key() {
+        tokio_test::block_on(async {
+            let flights = SingleFlight::default();
+            let loads = AtomicUsize::new(0);
+
+            let load = |key: &'static str| {
+                let (flights, loads) = (&flights, &loads);
+                flights.run(key, move || async move {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    tokio::task::yield_now().await;
+                    Ok(format!("loaded {}", key))
+                })
+            };
+            let results = futures::future::join_all(["a", "a", "b", "a", "b"].map(load)).await;
+
+            assert_eq!(loads.load(Ordering::SeqCst), 2);
+            assert_eq!(results[3].as_ref().unwrap(), "loaded a");
+            assert_eq!(results[4].as_ref().unwrap(), "loaded b");
+            assert_eq!(flights.len(), 0);
+        })
+    }
+
+    #[test]
+    fn test_failed_load() {
+        tokio_test::block_on(async {
+            let flights = SingleFlight::default();
+            let loads = AtomicUsize::new(0);
+
+            // The first load fails, so the others load for themselves
+            let load = |_| {
+                let (flights, loads) = (&flights, &loads);
+                flights.run("a", move || async move {
+                    let load = loads.fetch_add(1, Ordering::SeqCst);
+                    tokio::task::yield_now().await;
+                    match load {
+                        0 => Err(DaoError::NotFound),
+                        _ => Ok(load),
+                    }
+                })
+            };
+            let results = futures::future::join_all([0, 1, 2].map(load)).await;
+
+            assert!(matches!(results[0], Err(DaoError::NotFound)));
+            assert!(results[1].is_ok() && results[2].is_ok());
+            assert_eq!(loads.load(Ordering::SeqCst), 3);
+            assert_eq!(flights.len(), 0);
+        })
+    }
+}
@@ -0,0 +1,299 @@
+/// Backend neutral partial updates, for use with [PersistStore::update_with](crate::PersistStore::update_with).
+///
+/// An [Update] is a list of operations that are applied to a single object in one atomic step.
+/// Values are held as JSON, the same as in a [Filter](crate::Filter), so MongoDB specific types
+/// such as ObjectIds and dates are turned back into their native BSON types by [DB](crate::DB).
+///
+/// Keys may be dotted (`"a.b.c"`) to reach into nested objects.  Use each key in at most one
+/// operation per update.
+///
+/// Example
+/// ```rust
+/// use swanky_persist::Update;
+///
+/// let update = Update::new()
+///     .set("status", "active")
+///     .set("address", serde_json::json!({"city": "Leeds"}))
+///     .unset("suspended_at")
+///     .inc("logins", 1)
+///     .add_to_set("roles", "editor");
+/// ```
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+
+use crate::{db::lookup, filter::equal, DaoError, DaoResult};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpdateOp {
+    /// Set the field to the value, creating any missing parents
+    Set(String, Value),
+    /// Remove the field
+    Unset(String),
+    /// Add the value to the numeric field, treating a missing field as zero
+    Inc(String, Value),
+    /// Append the value to the array field, creating the array if missing
+    Push(String, Value),
+    /// Remove every element equal to the value from the array field
+    Pull(String, Value),
+    /// Append the value to the array field unless an equal element is already there
+    AddToSet(String, Value),
+}
+
+#[derive(Debug, Default)]
+pub struct Update {
+    operations: Vec<UpdateOp>,
+    /// The first value that failed to serialize, reported when the update is run
+    error: Option<DaoError>,
+}
+
+impl Update {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set<V: Serialize>(self, key: impl Into<String>, value: V) -> Self {
+        self.push_op(key.into(), &value, UpdateOp::Set)
+    }
+
+    /// The value the update sets `key` to, if it sets it.
+    pub(crate) fn set_value(&self, key: &str) -> Option<&Value> {
+        self.operations.iter().rev().find_map(|op| match op {
+            UpdateOp::Set(set, value) if set == key => Some(value),
+            _ => None,
+        })
+    }
+
+    pub fn unset(mut self, key: impl Into<String>) -> Self {
+        self.operations.push(UpdateOp::Unset(key.into()));
+        self
+    }
+
+    pub fn inc<V: Serialize>(self, key: impl Into<String>, value: V) -> Self {
+        self.push_op(key.into(), &value, UpdateOp::Inc)
+    }
+
+    pub fn push<V: Serialize>(self, key: impl Into<String>, value: V) -> Self {
+        self.push_op(key.into(), &value, UpdateOp::Push)
+    }
+
+    pub fn pull<V: Serialize>(self, key: impl Into<String>, value: V) -> Self {
+        self.push_op(key.into(), &value, UpdateOp::Pull)
+    }
+
+    pub fn add_to_set<V: Serialize>(self, key: impl Into<String>, value: V) -> Self {
+        self.push_op(key.into(), &value, UpdateOp::AddToSet)
+    }
+
+    /// Whether the update has no operations.
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    fn push_op<V: Serialize>(
+        mut self,
+        key: String,
+        value: &V,
+        op: fn(String, Value) -> UpdateOp,
+    ) -> Self {
+        match serde_json::to_value(value) {
+            Ok(value) => self.operations.push(op(key, value)),
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(DaoError::serialization::<V>(&key, e));
+                }
+            }
+        }
+        self
+    }
+
+    /// The operations to run, or the error from building them.
+    pub(crate) fn into_operations(self) -> DaoResult<Vec<UpdateOp>> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.operations),
+        }
+    }
+}
+
+impl From<UpdateOp> for Update {
+    fn from(op: UpdateOp) -> Self {
+        Self {
+            operations: vec![op],
+            error: None,
+        }
+    }
+}
+
+impl FromIterator<UpdateOp> for Update {
+    fn from_iter<I: IntoIterator<Item = UpdateOp>>(iter: I) -> Self {
+        Self {
+            operations: iter.into_iter().collect(),
+            error: None,
+        }
+    }
+}
+
+/// Apply update operations to a JSON document, for stores without native partial updates.
+/// Nothing is changed if any operation fails.
+pub(crate) fn apply_update(document: &mut Value, operations: &[UpdateOp]) -> DaoResult<()> {
+    let mut updated = document.clone();
+    for op in operations {
+        match op {
+            UpdateOp::Set(key, value) => set_path(&mut updated, key, value.clone())?,
+            UpdateOp::Unset(key) => {
+                remove_path(&mut updated, key);
+            }
+            UpdateOp::Inc(key, value) => {
+                let sum = match lookup(&updated, key) {
+                    None => Some(value.clone()),
+                    Some(Value::Number(current)) => add_numbers(current, value),
+                    Some(_) => None,
+                }
+                .ok_or_else(|| {
+                    DaoError::ServiceError(format!("Update: can't increment {} by {}", key, value))
+                })?;
+                set_path(&mut updated, key, sum)?;
+            }
+            UpdateOp::Push(key, value) | UpdateOp::AddToSet(key, value) => {
+                let mut array = array_at(&updated, key)?;
+                let present = array.iter().any(|element| equal(element, value));
+                if matches!(op, UpdateOp::Push(..)) || !present {
+                    array.push(value.clone());
+                }
+                set_path(&mut updated, key, Value::Array(array))?;
+            }
+            UpdateOp::Pull(key, value) => {
+                if lookup(&updated, key).is_some() {
+                    let mut array = array_at(&updated, key)?;
+                    array.retain(|element| !equal(element, value));
+                    set_path(&mut updated, key, Value::Array(array))?;
+                }
+            }
+        }
+    }
+    *document = updated;
+    Ok(())
+}
+
+/// Set a possibly dotted (`"a.b.c"`) key in a document, creating any missing parents.
+pub(crate) fn set_path(document: &mut Value, key: &str, value: Value) -> DaoResult<()> {
+    let mut parts = key.split('.').peekable();
+    let mut current = document;
+    while let Some(part) = parts.next() {
+        let object = current.as_object_mut().ok_or_else(|| {
+            DaoError::ServiceError(format!("Update: {} is not inside an object", key))
+        })?;
+        if parts.peek().is_none() {
+            object.insert(part.to_string(), value);
+            return Ok(());
+        }
+        current = object
+            .entry(part)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    Ok(())
+}
+
+/// Remove a possibly dotted (`"a.b.c"`) key from a document, returning the removed value.
+fn remove_path(document: &mut Value, key: &str) -> Option<Value> {
+    let (parent, last) = match key.rsplit_once('.') {
+        Some((parent, last)) => (
+            parent
+                .split('.')
+                .try_fold(document, |value, part| value.as_object_mut()?.get_mut(part))?,
+            last,
+        ),
+        None => (document, key),
+    };
+    parent.as_object_mut()?.remove(last)
+}
+
+/// A copy of the array at `key`, or an empty array if the field is missing.
+fn array_at(document: &Value, key: &str) -> DaoResult<Vec<Value>> {
+    match lookup(document, key) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(array)) => Ok(array.clone()),
+        Some(_) => Err(DaoError::ServiceError(format!(
+            "Update: {} is not an array",
+            key
+        ))),
+    }
+}
+
+/// Add two JSON numbers, keeping integers exact where possible.
+fn add_numbers(current: &Number, value: &Value) -> Option<Value> {
+    let Value::Number(value) = value else {
+        return None;
+    };
+    if let (Some(a), Some(b)) = (current.as_i64(), value.as_i64()) {
+        if let Some(sum) = a.checked_add(b) {
+            return Some(Value::from(sum));
+        }
+    }
+    Number::from_f64(current.as_f64()? + value.as_f64()?).map(Value::Number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_apply_update() {
+        let mut doc = json!({
+            "name": "Rex",
+            "age": 4,
+            "weight": 30.5,
+            "owner": {"name": "Sam", "phone": "123"},
+            "tags": ["dog", "friendly", "dog"],
+        });
+        let update = Update::new()
+            .set("owner.name", "Alex")
+            .set("vet.name", "Jo")
+            .unset("owner.phone")
+            .unset("missing.field")
+            .inc("age", 1)
+            .inc("weight", -0.5)
+            .inc("visits", 2)
+            .pull("tags", "dog")
+            .push("tricks", "sit")
+            .add_to_set("tags", "friendly")
+            .add_to_set("tags", "good");
+        apply_update(&mut doc, &update.into_operations().unwrap()).unwrap();
+        assert_eq!(
+            doc,
+            json!({
+                "name": "Rex",
+                "age": 5,
+                "weight": 30.0,
+                "owner": {"name": "Alex"},
+                "vet": {"name": "Jo"},
+                "visits": 2,
+                "tags": ["friendly", "good"],
+                "tricks": ["sit"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_failed_update_changes_nothing() {
+        let mut doc = json!({"name": "Rex", "age": 4});
+        let update = Update::new().set("age", 5).inc("name", 1);
+        assert!(apply_update(&mut doc, &update.into_operations().unwrap()).is_err());
+        assert_eq!(doc, json!({"name": "Rex", "age": 4}));
+
+        let update = Update::new().push("name", "x");
+        assert!(apply_update(&mut doc, &update.into_operations().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_unserializable_value() {
+        let mut map = std::collections::HashMap::new();
+        map.insert((1, 2), "tuple keys aren't valid JSON");
+        let update = Update::new().set("ok", 1).set("bad", &map);
+        assert!(matches!(
+            update.into_operations(),
+            Err(DaoError::Serialization { location, .. }) if location == "bad"
+        ));
+    }
+}
@@ -1,3 +1,22 @@
+/// How a [Cacheable] object is encoded in the cache.
+/// Formats other than JSON only exist with the matching `msgpack`, `cbor` or `bincode` feature
+/// of swanky_persist, so picking a disabled format fails to compile.  Matches need a wildcard
+/// arm, so that enabling another format elsewhere in the build doesn't break them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CacheFormat {
+    #[default]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// Compact, but can't decode types that need self describing data, such as untagged enums
+    /// or `serde_json::Value` fields
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
 /// Manages cache details at the object level.
 /// Each cacheable object defines its own path into the Redis key namespace as well
 /// as it's cache lifetime (in seconds).
@@ -9,4 +28,20 @@ pub trait Cacheable {
     fn cache_id(&self) -> String;
     /// Cache lifetime for this object (in seconds))
     fn cache_expiry() -> usize;
+    /// How long to remember that an id has no object (in seconds), so repeated lookups of it
+    /// don't reach the database.  `None`, the default, doesn't cache misses, and nor does
+    /// `Some(0)`.
+    fn cache_miss_expiry() -> Option<usize> {
+        None
+    }
+    /// Version of the cached form of this object.  Bump it when the struct changes shape, so
+    /// entries cached by older code are keyed apart instead of failing to decode.  `None`, the
+    /// default, leaves the version out of the key.
+    fn cache_version() -> Option<u32> {
+        None
+    }
+    /// Format this object is encoded in when cached.  Defaults to JSON.
+    fn cache_format() -> CacheFormat {
+        CacheFormat::Json
+    }
 }
@@ -3,6 +3,11 @@
 //! ### Struct Attributes
 //! * **path:** `String`: The collection name for the struct. Defaults to the struct name.
 //! * **expiry** `usize`: The cache expiry time.  Defaults to 3600.
+//! * **miss_expiry** `usize`: How long to cache that an id has no object, at least 1.  Misses aren't cached by default.
+//! * **version** `u32`: The schema version included in cache keys.  Left out of keys by default.
+//! * **format** `String`: How the struct is encoded in the cache: one of `json`, `msgpack`, `cbor` or `bincode`.
+//!   Defaults to `json`.  Formats other than `json` need the matching swanky_persist feature, and
+//!   `CacheFormat` in scope.
 //! * **id_func:** `Expr`: An otional expression to return an id value, if returning a field value is insufficient.
 //!
 //! ### Field Attributes
@@ -10,10 +15,10 @@
 //!
 //! Example
 //! ```rust, ignore
-//! use swanky_persist::{Cache, Cacheable};
+//! use swanky_persist::{Cache, CacheFormat, Cacheable};
 //!
 //! #[derive(Cache)]
-//! #[cache(path = "foo-cache", expiry = 3600)]
+//! #[cache(path = "foo-cache", expiry = 3600, miss_expiry = 60, format = "msgpack")]
 //! struct Foo {
 //!     #[cache(id)]
 //!     _id: String,
@@ -45,6 +50,9 @@ struct CacheOpts {
     ident: Ident,
     path: Option<String>,
     expiry: Option<usize>,
+    miss_expiry: Option<usize>,
+    version: Option<u32>,
+    format: Option<String>,
     id_func: Option<Expr>,
     data: ast::Data<util::Ignored, CacheField>,
 }
@@ -130,6 +138,43 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
         pub const #cache_expiry_key: usize = #expiry;
     };
 
+    if opts.miss_expiry == Some(0) {
+        panic!("#[cache(miss_expiry)] must be at least 1 second");
+    }
+    let miss_expiry_func = opts.miss_expiry.map(|miss_expiry| {
+        quote! {
+            fn cache_miss_expiry() -> Option<usize> {
+                Some(#miss_expiry)
+            }
+        }
+    });
+
+    let version_func = opts.version.map(|version| {
+        quote! {
+            fn cache_version() -> Option<u32> {
+                Some(#version)
+            }
+        }
+    });
+
+    let format_func = opts.format.as_ref().map(|format| {
+        let variant = match format.as_str() {
+            "json" => format_ident!("Json"),
+            "msgpack" => format_ident!("MessagePack"),
+            "cbor" => format_ident!("Cbor"),
+            "bincode" => format_ident!("Bincode"),
+            other => panic!(
+                "#[cache(format = \"{}\")] must be one of json, msgpack, cbor or bincode",
+                other
+            ),
+        };
+        quote! {
+            fn cache_format() -> CacheFormat {
+                CacheFormat::#variant
+            }
+        }
+    });
+
     // Set the static str for the collection name field
     let cache_path_key = format_ident!("{}_CACHE_PATH", ident.to_string().to_uppercase());
     let cache_path_const = match opts.path {
@@ -154,6 +199,9 @@ pub fn derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
             fn cache_expiry() -> usize {
                 #cache_expiry_key
             }
+            #miss_expiry_func
+            #version_func
+            #format_func
             #id_func
         }
     };